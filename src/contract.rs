@@ -1,20 +1,37 @@
 use std::collections::HashMap;
 
-use cosmwasm_std::{ensure, from_json, to_json_binary, Addr, IbcBasicResponse, IbcMsg, IbcTimeout, Timestamp};
+use cosmwasm_std::{ensure, from_json, to_json_binary, Addr, Api, Coin, Event, IbcFee, IbcMsg, IbcTimeout, IbcTimeoutBlock, Order, Storage, Timestamp, Uint128};
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::{entry_point, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult};
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
+use cw20::Cw20ReceiveMsg;
+use cw721::{Cw721QueryMsg, OwnerOfResponse};
 
-use crate::{datatypes::{Cw721ReceiveMsg, IbcPacketOutgoing, NftReceiveMsg, PacketType, State, UserData}, helpers::{ensure_error, standard_error}, msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, UpdateStatePayload}, queries::{get_all_pending_packets, get_all_users_data, get_state, get_token_status, get_user_data, get_user_pending_packets}, state::{CHANNEL, PENDING_PACKETS_REQUESTS, STATE, UNIQUE_PACKETS_REQUEST_ID, USERS_DATA}, ContractError};
+use crate::datatypes::{AssetRevenue, AttemptOutcome, Campaign, ChannelInfo, ChannelProtocol, Cw20HookMsg, Cw721ReceiveMsg, EmergencyRelease, EscrowedFee, HistoryAction, HostInfo, IbcPacketOutgoing, IbcSettings, NftReceiveMsg, PacketType, PaymentAsset, PendingPacket, PendingStatus, RequestAttempt, State, TimeoutKind, UserData};
+use crate::helpers::{describe_timeout, ensure_error, ensure_min_lock_duration, format_assets, is_emergency_released, is_token_locked, load_collection_info, load_collection_route, load_host_disconnected_since, load_host_route, load_token_requests, record_attempt_outcome, record_history, refund_credits, release_fee_escrow, remove_locked_token, remove_pending_request, save_pending_request, send_assets, send_nft, standard_error};
+use crate::migrations::migrate_from_v0_2;
+use crate::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, UpdateStatePayload};
+use crate::protocol;
+use crate::queries::{get_all_pending_packets, get_all_users_data, get_campaign, get_campaign_grant, get_campaigns, get_channel_health, get_channels, get_pending_request, get_relayer_fee_pool, get_request_attempts, get_revenue, get_state, get_token_history, get_token_lock, get_token_status, get_unlock_timeouts, get_user_data, get_user_history, get_user_pending_packets, simulate_credit_purchase};
+use crate::state::{CAMPAIGNS, CAMPAIGN_GRANTS, CHANNELS, CHANNELS_HEALTH, CHANNEL_PENDING_REQUESTS, EMERGENCY_RELEASES, HOST_CHANNELS, HOST_HEIGHTS, LAST_TOKEN_LOCKS, LOCKED_TOKENS, PENDING_PACKETS_REQUESTS, PENDING_REQUEST_TIMEOUTS, RELAYER_FEE_ESCROWS, RELAYER_FEE_POOL, REQUEST_ATTEMPTS, REVENUE, STATE, TIMED_OUT_UNLOCK_REQUESTS, TOKEN_PENDING_REQUESTS, UNIQUE_PACKETS_REQUEST_ID, USERS_DATA};
+use crate::ContractError;
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:gamefi_satellite";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
-const MIGRATE_VERSION: &str = "0.2.0";
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
-    set_contract_version(deps.storage, CONTRACT_NAME, MIGRATE_VERSION)?;
+pub fn migrate(mut deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
+    let stored_version = get_contract_version(deps.storage)?;
+    ensure!(stored_version.contract == CONTRACT_NAME, ensure_error(format!("Can't migrate from contract {}", stored_version.contract)));
+
+    match stored_version.version.as_str() {
+        "0.2.0" => migrate_from_v0_2(deps.branch())?,
+        CONTRACT_VERSION => (),
+        version => return Err(ensure_error(format!("Can't migrate from version {}", version)))
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     Ok(Response::default())
 }
 
@@ -27,10 +44,13 @@ pub fn instantiate(deps: DepsMut, _env: Env, info: MessageInfo, msg: Instantiate
         collections_info : msg.collections_info,
         admin : info.sender.clone(),
         ibc_settings : msg.ibc_settings,
-        host_chain_prefix : msg.host_chain_prefix,
+        hosts : msg.hosts,
         lock_credit_settings : msg.lock_credit_settings
     };
 
+    validate_hosts(&state)?;
+    validate_ibc_settings(&state)?;
+    validate_lock_credit_settings(deps.api, &state)?;
     STATE.save(deps.storage, &state)?;
     UNIQUE_PACKETS_REQUEST_ID.save(deps.storage, &0u128)?;
     
@@ -43,7 +63,20 @@ pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: ExecuteMsg) ->
         ExecuteMsg::ReceiveNft(message) => init_lock_procedure(deps, _env, info, message),
         ExecuteMsg::UnlockToken { collection, token_id , native_address} => init_unlock_procedure(deps, _env, info, collection, token_id, native_address),
         ExecuteMsg::UpdateStatePayload { state_changes } => update_state(deps, info, state_changes),
-        ExecuteMsg::GetCredits { amount } => purchase_credits(deps, info, amount)
+        ExecuteMsg::GetCredits { amount } => purchase_credits(deps, _env, info, amount),
+        ExecuteMsg::Receive(cw20_msg) => receive_cw20(deps, _env, info, cw20_msg),
+        ExecuteMsg::BindChannel { channel_id, host, fee_enabled } => bind_channel(deps, info, channel_id, host, fee_enabled.unwrap_or_default()),
+        ExecuteMsg::RecoverClosedChannel { channel_id, limit } => recover_closed_channel(deps, _env, channel_id, limit),
+        ExecuteMsg::EmergencyUnlock { collection, token_id } => emergency_unlock(deps, _env, info, collection, token_id),
+        ExecuteMsg::LockTokens { tokens, native_address } => init_batch_lock_procedure(deps, _env, info, tokens, native_address),
+        ExecuteMsg::UnlockTokens { tokens, native_address } => init_batch_unlock_procedure(deps, _env, info, tokens, native_address),
+        ExecuteMsg::Ping { host } => ping(deps, _env, info, host),
+        ExecuteMsg::RetryUnlock { request_id } => retry_unlock(deps, _env, info, request_id),
+        ExecuteMsg::ResetUnlockTimeouts { collection, token_id, user } => reset_unlock_timeouts(deps, info, collection, token_id, user),
+        ExecuteMsg::PrunePending { limit } => prune_pending(deps, _env, limit),
+        ExecuteMsg::WithdrawFees {} => withdraw_fees(deps, info),
+        ExecuteMsg::GrantCredits { recipients, campaign } => grant_credits(deps, _env, info, recipients, campaign),
+        ExecuteMsg::CreateCampaign { name, budget, per_user_cap, expires_at } => create_campaign(deps, info, name, budget, per_user_cap, expires_at)
     }
 }
 
@@ -56,6 +89,20 @@ pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
         QueryMsg::GetState{}=>to_json_binary(&get_state(deps)?),
         QueryMsg::GetTokenStatus { user, collection, token_id } => to_json_binary(&get_token_status(deps, user, collection, token_id)?),
         QueryMsg::GetUserPendingPackets{start_after,limit, user}=>to_json_binary(&get_user_pending_packets(deps,start_after,limit, user)?),
+        QueryMsg::GetChannels{start_after,limit}=>to_json_binary(&get_channels(deps,start_after,limit)?),
+        QueryMsg::GetRelayerFeePool{}=>to_json_binary(&get_relayer_fee_pool(deps)?),
+        QueryMsg::GetCampaign{name}=>to_json_binary(&get_campaign(deps,name)?),
+        QueryMsg::GetCampaigns{start_after,limit}=>to_json_binary(&get_campaigns(deps,start_after,limit)?),
+        QueryMsg::GetCampaignGrant{name,user}=>to_json_binary(&get_campaign_grant(deps,name,user)?),
+        QueryMsg::GetRevenue{}=>to_json_binary(&get_revenue(deps)?),
+        QueryMsg::SimulateCreditPurchase{amount}=>to_json_binary(&simulate_credit_purchase(deps,_env,amount)?),
+        QueryMsg::GetPendingRequest{user, request_id}=>to_json_binary(&get_pending_request(deps, user, request_id)?),
+        QueryMsg::GetChannelHealth{channel_id}=>to_json_binary(&get_channel_health(deps, channel_id)?),
+        QueryMsg::GetRequestAttempts{user, request_id}=>to_json_binary(&get_request_attempts(deps, user, request_id)?),
+        QueryMsg::GetTokenLock{collection, token_id}=>to_json_binary(&get_token_lock(deps, collection, token_id)?),
+        QueryMsg::GetUnlockTimeouts{collection, token_id, user}=>to_json_binary(&get_unlock_timeouts(deps, collection, token_id, user)?),
+        QueryMsg::GetUserHistory{user, start_after, limit}=>to_json_binary(&get_user_history(deps, user, start_after, limit)?),
+        QueryMsg::GetTokenHistory{collection, token_id, start_after, limit}=>to_json_binary(&get_token_history(deps, collection, token_id, start_after, limit)?),
     }
 }
 
@@ -82,38 +129,390 @@ pub fn update_state(
     if let Some(ibc_settings) = state_changes.ibc_settings {
         state.ibc_settings = ibc_settings;
     }
-    if let Some(host_chain_prefix) = state_changes.host_chain_prefix {
-        state.host_chain_prefix = host_chain_prefix;
+    if let Some(hosts) = state_changes.hosts {
+        state.hosts = hosts;
     }
     if let Some(lock_credit_settings) = state_changes.lock_credit_settings {
         state.lock_credit_settings = lock_credit_settings;
     }
 
+    validate_hosts(&state)?;
+    validate_ibc_settings(&state)?;
+    validate_lock_credit_settings(deps.api, &state)?;
+    STATE.save(deps.storage, &state)?;
+
     Ok(
         Response::default()
         .add_attribute("action", "state changed")
     )
 }
 
+//Every collection must be routed to a known host, and host labels must be unique
+fn validate_hosts(state: &State) -> Result<(), ContractError> {
+    let mut labels = state.hosts.iter().map(|host| host.label.clone()).collect::<Vec<_>>();
+    labels.sort();
+    labels.dedup();
+
+    if labels.len() != state.hosts.len() {
+        return Err(ContractError::ValidationError { field: "hosts".to_string() })
+    }
+
+    if state.collections_info.iter().any(|collection| !labels.contains(&collection.host)) {
+        return Err(ContractError::ValidationError { field: "collections_info".to_string() })
+    }
+
+    Ok(())
+}
+
+fn validate_ibc_settings(state: &State) -> Result<(), ContractError> {
+    if state.ibc_settings.relayer_fees.as_ref().is_some_and(|fees| fees.revenue_share > 100) {
+        return Err(ContractError::ValidationError { field: "ibc_settings.relayer_fees.revenue_share".to_string() })
+    }
+
+    if let Some(fee_settings) = &state.ibc_settings.relayer_fees {
+        let mut denoms = fee_settings.fees.iter().map(|fee| fee.denom.clone()).collect::<Vec<_>>();
+        denoms.sort();
+        denoms.dedup();
+        if denoms.len() != fee_settings.fees.len() {
+            return Err(ContractError::ValidationError { field: "ibc_settings.relayer_fees.fees".to_string() })
+        }
+    }
+
+    if state.ibc_settings.retry_backoff.is_some_and(|backoff| backoff < 2) {
+        return Err(ContractError::ValidationError { field: "ibc_settings.retry_backoff".to_string() })
+    }
+
+    if state.ibc_settings.timeout_kind != TimeoutKind::Timestamp && state.hosts.iter().any(|host| host.height_timeout.is_none()) {
+        return Err(ContractError::ValidationError { field: "hosts.height_timeout".to_string() })
+    }
+
+    Ok(())
+}
+
+//The treasury splits must share the whole revenue between valid addresses, and promotions must be bounded discounts
+fn validate_lock_credit_settings(api: &dyn Api, state: &State) -> Result<(), ContractError> {
+    if state.lock_credit_settings.promotions.iter().any(|promotion| promotion.start >= promotion.end || promotion.discount > 100) {
+        return Err(ContractError::ValidationError { field: "lock_credit_settings.promotions".to_string() })
+    }
+
+    if let Some(treasury) = &state.lock_credit_settings.treasury {
+        if treasury.splits.iter().map(|split| split.percent as u32).sum::<u32>() != 100 {
+            return Err(ContractError::ValidationError { field: "lock_credit_settings.treasury.splits".to_string() })
+        }
+
+        if api.addr_validate(treasury.manager.as_str()).is_err() {
+            return Err(ContractError::ValidationError { field: "lock_credit_settings.treasury.manager".to_string() })
+        }
+
+        if treasury.splits.iter().any(|split| api.addr_validate(split.address.as_str()).is_err()) {
+            return Err(ContractError::ValidationError { field: "lock_credit_settings.treasury.splits".to_string() })
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Bind an open channel to a host, every lock/unlock of the host collections will be sent through it.
+ * A host has a single bound channel, binding a new one replaces the previous.
+ */
+fn bind_channel(deps: DepsMut, info: MessageInfo, channel_id: String, host: String, fee_enabled: bool) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+
+    ensure!(state.admin == info.sender, ContractError::Unauthorized {});
+    ensure!(state.hosts.iter().any(|host_info| host_info.label == host), ensure_error(format!("Host {} not found", host)));
+
+    let mut channel_info = CHANNELS.may_load(deps.storage, channel_id.clone())?
+        .ok_or_else(|| ensure_error(format!("Channel {} not found", channel_id)))?;
+    ensure!(channel_info.finalized, ensure_error("Can't bind a channel that is not open".to_string()));
+
+    //Unbind the channel from the previous host, if any
+    if let Some(previous_host) = channel_info.host.clone() {
+        if HOST_CHANNELS.may_load(deps.storage, previous_host.clone())? == Some(channel_id.clone()) {
+            HOST_CHANNELS.remove(deps.storage, previous_host);
+        }
+    }
+
+    //Unbind the previous channel of the host, if any
+    if let Some(previous_channel_id) = HOST_CHANNELS.may_load(deps.storage, host.clone())? {
+        CHANNELS.update(deps.storage, previous_channel_id, |previous_channel| -> StdResult<_> {
+            let mut previous_channel = previous_channel.ok_or_else(|| ensure_error("Channel not found".to_string()))?;
+            previous_channel.host = None;
+            Ok(previous_channel)
+        })?;
+    }
+
+    channel_info.host = Some(host.clone());
+    channel_info.fee_enabled = fee_enabled;
+    CHANNELS.save(deps.storage, channel_id.clone(), &channel_info)?;
+    HOST_CHANNELS.save(deps.storage, host.clone(), &channel_id)?;
+
+    Ok(
+        Response::default()
+        .add_attribute("action", "channel bound")
+        .add_attribute("channel_id", channel_id)
+        .add_attribute("host", host)
+        .add_attribute("fee_enabled", fee_enabled.to_string())
+    )
+}
+
+/**
+ * Settle the requests stranded on a closed channel, callable by anyone, in batches of `limit`.
+ * Pending locks are refunded and the NFTs sent back to their owners,
+ * pending unlocks are marked recoverable and can be sent again once a new channel is bound to the host.
+ */
+fn recover_closed_channel(deps: DepsMut, env: Env, channel_id: String, limit: Option<u16>) -> Result<Response, ContractError> {
+    let channel_info = CHANNELS.may_load(deps.storage, channel_id.clone())?
+        .ok_or_else(|| ensure_error(format!("Channel {} not found", channel_id)))?;
+    ensure!(channel_info.closed_at.is_some(), ensure_error("The channel is not closed".to_string()));
+
+    let limit = limit.unwrap_or(10) as usize;
+
+    //Settled requests leave the channel index, every batch starts from its first entry
+    let stranded_requests = CHANNEL_PENDING_REQUESTS
+        .sub_prefix(channel_id.clone())
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "channel recovery")
+        .add_attribute("channel_id", channel_id);
+
+    for (user, request_id) in stranded_requests {
+        let mut pending = PENDING_PACKETS_REQUESTS.load(deps.storage, (user.clone(), request_id))?;
+        let tokens = pending.packet.packet_type.tokens();
+        record_attempt_outcome(deps.storage, &user, request_id, AttemptOutcome::ChannelClosed)?;
+        release_fee_escrow(deps.storage, &user, request_id, |packet| *packet == pending.packet, AttemptOutcome::ChannelClosed)?;
+
+        if pending.packet.packet_type.is_lock() {
+            refund_credits(deps.storage, &user, pending.credits)?;
+            remove_pending_request(deps.storage, &user, request_id)?;
+
+            //Tokens of the batch reclaimed with an emergency unlock were already sent back
+            for (collection, token_id) in tokens {
+                if is_emergency_released(deps.storage, request_id, &collection, &token_id)? {
+                    continue;
+                }
+
+                record_history(deps.storage, env.block.time.seconds(), &user, &collection, &token_id, Some(request_id), HistoryAction::LockFailed { reason : "channel_closed".to_string() })?;
+                response = response
+                    .add_attribute("refunded lock", format!("{}:{}", collection, token_id))
+                    .add_message(send_nft(collection, token_id, user.to_string()));
+            }
+        } else {
+            pending.status = PendingStatus::Recoverable;
+            save_pending_request(deps.storage, &user, request_id, &pending)?;
+
+            for (collection, token_id) in tokens {
+                response = response.add_attribute("recoverable unlock", format!("{}:{}", collection, token_id));
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+/**
+ * Let the locker reclaim a token when the host can't be reached, without any relayer involved.
+ * Allowed once the grace period is elapsed since the host lost its channel,
+ * or since a request of the user on that token was sent without any ack/timeout coming back,
+ * as long as the channel relayer didn't bring back any ack in the meantime (it would relay the timeout as well).
+ * The release is recorded, any late ack for a packet sent before it is ignored.
+ */
+fn emergency_unlock(deps: DepsMut, env: Env, info: MessageInfo, collection: String, token_id: String) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let current_time = env.block.time.seconds();
+
+    let token_requests = load_token_requests(deps.storage, &info.sender, &collection, &token_id)?;
+
+    //A lock made recoverable (pruned, or answered with an invalid ack) can only be settled by reclaiming the token,
+    //it is allowed even with emergency unlocks disabled
+    let lock_recoverable = token_requests.iter().any(|(_, pending)| pending.packet.packet_type.is_lock() && pending.status == PendingStatus::Recoverable);
+    let grace = state.ibc_settings.emergency_unlock_grace;
+    ensure!(grace.is_some() || lock_recoverable, ensure_error("Emergency unlock is disabled".to_string()));
+
+    let is_locked = is_token_locked(deps.storage, &info.sender, &collection, &token_id)?;
+    let is_lock_pending = token_requests.iter().any(|(_, pending)| pending.packet.packet_type.is_lock());
+    ensure!(is_locked || is_lock_pending, ensure_error("The token is not locked in the contract, or not owned by the user".to_string()));
+
+    let mut host_down = false;
+    let mut request_stuck = lock_recoverable;
+    if let Some(grace) = grace {
+        host_down = load_host_disconnected_since(deps.storage, &state, &collection)?
+            .is_some_and(|disconnected_since| disconnected_since + grace <= current_time);
+        for (_, pending) in token_requests.iter().filter(|(_, pending)| pending.status == PendingStatus::InFlight && pending.sent_at + grace <= current_time) {
+            let relayer_alive = CHANNELS_HEALTH.may_load(deps.storage, pending.channel_id.clone())?
+                .and_then(|health| health.last_ack_at)
+                .is_some_and(|last_ack_at| last_ack_at > pending.sent_at && last_ack_at + grace > current_time);
+            request_stuck |= !relayer_alive;
+        }
+    }
+    ensure!(host_down || request_stuck, ensure_error("The host is reachable, emergency unlock is not available yet".to_string()));
+
+    //Drop the token from the ledger and settle every request on it,
+    //batch requests are kept for their other tokens, the ack skips the released one
+    remove_locked_token(deps.storage, &info.sender, &collection, &token_id)?;
+
+    for (request_id, mut pending) in token_requests {
+        let mut active_tokens = 0u16;
+        for (request_collection, request_token_id) in pending.packet.packet_type.tokens() {
+            if !is_emergency_released(deps.storage, request_id, &request_collection, &request_token_id)? {
+                active_tokens += 1;
+            }
+        }
+        let token_credits = pending.credits.checked_div(active_tokens).unwrap_or_default();
+
+        if pending.packet.packet_type.is_lock() {
+            refund_credits(deps.storage, &info.sender, token_credits)?;
+        }
+
+        if active_tokens <= 1 {
+            remove_pending_request(deps.storage, &info.sender, request_id)?;
+        } else {
+            pending.credits -= token_credits;
+            save_pending_request(deps.storage, &info.sender, request_id, &pending)?;
+        }
+    }
+
+    TOKEN_PENDING_REQUESTS.remove(deps.storage, (collection.clone(), token_id.clone()));
+    TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, (collection.clone(), token_id.clone(), info.sender.clone()));
+    record_history(deps.storage, current_time, &info.sender, &collection, &token_id, None, HistoryAction::EmergencyUnlock)?;
+    let last_request_id = UNIQUE_PACKETS_REQUEST_ID.load(deps.storage)?;
+    EMERGENCY_RELEASES.save(deps.storage, (collection.clone(), token_id.clone()), &EmergencyRelease {
+        user : info.sender.clone(),
+        released_at : current_time,
+        last_request_id
+    })?;
+
+    Ok(
+        Response::new()
+        .add_attribute("action", "emergency unlock")
+        .add_attribute("reason", if host_down { "host_unreachable" } else { "request_stuck" })
+        .add_attribute("user", info.sender.to_string())
+        .add_attribute("token_id", token_id.clone())
+        .add_message(send_nft(collection, token_id, info.sender.to_string()))
+    )
+}
+
+/**
+ * Send a heartbeat to the host through its bound channel, callable by anyone at a limited rate.
+ * The ack (or the timeout) updates the channel health, the packet has no other effect.
+ */
+fn ping(deps: DepsMut, env: Env, info: MessageInfo, host: String) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let ping_interval = state.ibc_settings.ping_interval
+        .ok_or_else(|| ensure_error("Pings are disabled".to_string()))?;
+    let current_time = env.block.time.seconds();
+
+    let (host_info, channel_info) = load_host_route(deps.storage, &state, &host)?;
+    ensure!(channel_info.finalized, ensure_error("The channel bound to the host is not open".to_string()));
+    ensure!(channel_info.protocol != ChannelProtocol::Ics721, ensure_error("ICS-721 channels don't support heartbeats".to_string()));
+
+    let mut health = CHANNELS_HEALTH.may_load(deps.storage, channel_info.channel_id.clone())?.unwrap_or_default();
+    ensure!(
+        health.last_ping_at.is_none_or(|last_ping_at| last_ping_at + ping_interval <= current_time),
+        ensure_error(format!("A ping can be sent once every {} seconds", ping_interval))
+    );
+
+    //The pong refreshes the host height, the ping can't depend on it and always times out on a timestamp
+    let request_id = UNIQUE_PACKETS_REQUEST_ID.load(deps.storage)? + 1;
+    let timeout = IbcTimeout::with_timestamp(env.block.time.plus_seconds(state.ibc_settings.timeout));
+    let ping = IbcPacketOutgoing {
+        packet_type : PacketType::Ping { sender : info.sender.clone() },
+        chain_prefix : host_info.chain_prefix.clone(),
+        timestamp : current_time,
+        request_id
+    };
+
+    let ibc_message = IbcMsg::SendPacket {
+        channel_id : channel_info.channel_id.clone(),
+        data : protocol::encode_request(&channel_info.protocol, &ping, &host_info)?,
+        timeout : timeout.clone()
+    };
+
+    health.last_ping_at = Some(current_time);
+    CHANNELS_HEALTH.save(deps.storage, channel_info.channel_id.clone(), &health)?;
+    UNIQUE_PACKETS_REQUEST_ID.save(deps.storage, &request_id)?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "ping")
+        .add_attribute("channel_id", channel_info.channel_id)
+        .add_attribute("ping timeout", describe_timeout(&timeout));
+
+    if !cfg!(test) {
+        response = response.add_message(ibc_message);
+    }
+
+    Ok(response)
+}
+
 /**
  * Credits can be purchased by sending the required tokens to the contract,
- * the amount of credits purchased is based on the lock_credit_settings in the state
+ * the amount of credits purchased is based on the price list in the lock_credit_settings of the state
  * the credits are used to lock tokens, if the user has no credits he can't lock tokens (optional)
+ * only the exact price is kept, any other fund is refunded in the same transaction
  */
-fn purchase_credits(deps: DepsMut, info: MessageInfo, amount: u16) -> Result<Response, ContractError> {
+fn purchase_credits(deps: DepsMut, env: Env, info: MessageInfo, amount: u16) -> Result<Response, ContractError> {
     let state = STATE.load(deps.storage)?;
 
     if info.funds.is_empty() {
         return standard_error("No funds sent".to_string())
     }
 
-    let sent_funds = info.funds[0].clone();
+    let sent : Vec<(PaymentAsset, Uint128)> = info.funds
+        .iter()
+        .map(|coin| (PaymentAsset::Native { denom : coin.denom.clone() }, coin.amount))
+        .collect();
 
-    let mut user_data = match USERS_DATA.may_load(deps.storage, info.sender.clone()) {
+    //Credits are free for the admin
+    if info.sender == state.admin {
+        return add_purchased_credits(deps.storage, &env, &state, &info.sender, amount, sent, None)
+    }
+
+    //The first sent coin listed in the prices pays the credits
+    let Some((asset, _)) = sent.iter().find(|(asset, _)| state.lock_credit_settings.accepts(asset)) else {
+        return standard_error(format!("Invalid funds sent, accepted assets: {}", accepted_assets(&state)))
+    };
+
+    let asset = asset.clone();
+    add_purchased_credits(deps.storage, &env, &state, &info.sender, amount, sent, Some(asset))
+}
+
+/**
+ * Credits paid with a cw20 token, the cw20 contract calls the satellite with the Send of the user
+ */
+fn receive_cw20(deps: DepsMut, env: Env, info: MessageInfo, cw20_msg: Cw20ReceiveMsg) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let user = deps.api.addr_validate(&cw20_msg.sender)?;
+
+    match from_json(&cw20_msg.msg)? {
+        Cw20HookMsg::GetCredits { amount } => {
+            let asset = PaymentAsset::Cw20 { address : info.sender.to_string() };
+
+            if !state.lock_credit_settings.accepts(&asset) {
+                return standard_error(format!("Invalid funds sent, accepted assets: {}", accepted_assets(&state)))
+            }
+
+            add_purchased_credits(deps.storage, &env, &state, &user, amount, vec![(asset.clone(), cw20_msg.amount)], Some(asset))
+        }
+    }
+}
+
+fn accepted_assets(state: &State) -> String {
+    state.lock_credit_settings.prices.iter().map(|price| price.asset.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/**
+ * Charge the cost of the credits in `payment_asset`, with tiers and promotions, from the sent funds and add the credits to the user.
+ * Without a payment asset (admin) the credits are free, part of the native revenue is set aside for the relayers.
+ * Whatever is left of the sent funds goes back to the user.
+ */
+fn add_purchased_credits(storage: &mut dyn Storage, env: &Env, state: &State, user: &Addr, amount: u16, sent: Vec<(PaymentAsset, Uint128)>, payment_asset: Option<PaymentAsset>) -> Result<Response, ContractError> {
+    let mut user_data = match USERS_DATA.may_load(storage, user.clone()) {
         Ok(Some(data)) => data,
         Ok(None) => UserData { 
-            address : info.sender.clone(),
-            locked_tokens : HashMap::new(),
+            address : user.clone(),
             last_lock : 0,
             lock_credits : 0
         },
@@ -122,27 +521,412 @@ fn purchase_credits(deps: DepsMut, info: MessageInfo, amount: u16) -> Result<Res
         }
     };
 
-    if info.sender != state.admin {
-        match state.lock_credit_settings.token {
-            Some(token) => {
-                if token.denom != sent_funds.denom || token.amount.u128() * (amount as u128) > sent_funds.amount.u128() {
-                    return standard_error(format!("Invalid funds sent, required {} {}", token.amount.u128() * (amount as u128), token.denom))
-                }
+    let lock_credits = user_data.lock_credits.checked_add(amount)
+        .ok_or_else(|| ensure_error(format!("Too many credits purchased by {}", user)))?;
+    let mut price = vec![];
+    let mut refunded = sent.clone();
+
+    if let Some(asset) = payment_asset {
+        let total_price = state.lock_credit_settings.quote(&asset, amount, env.block.time.seconds())?.cost.u128();
+        let paid = sent.iter().find(|(sent_asset, _)| *sent_asset == asset).map(|(_, paid)| paid.u128()).unwrap_or_default();
+        if total_price > paid {
+            return standard_error(format!("Invalid funds sent, required {} {}", total_price, asset))
+        }
+
+        //Part of the revenue pays the relayers of the lock/unlock packets, in the native denoms relayer fees are set for
+        let mut relayer_share = 0;
+        match (&asset, &state.ibc_settings.relayer_fees) {
+            (PaymentAsset::Native { denom }, Some(fee_settings)) if fee_settings.fees.iter().any(|fee| fee.denom == *denom) => {
+                relayer_share = Uint128::new(total_price).multiply_ratio(fee_settings.revenue_share as u128, 100u128).u128();
+                RELAYER_FEE_POOL.update(storage, denom.clone(), |pool| -> StdResult<_> {
+                    Ok(pool.unwrap_or_default().checked_add(Uint128::new(relayer_share))?)
+                })?;
             },
-            None => { }
+            _ => ()
+        }
+
+        REVENUE.update(storage, asset.to_string(), |revenue| -> StdResult<_> {
+            let mut revenue = revenue.unwrap_or(AssetRevenue { asset : asset.clone(), collected : Uint128::zero(), relayer_share : Uint128::zero(), withdrawn : Uint128::zero() });
+            revenue.collected += Uint128::new(total_price);
+            revenue.relayer_share += Uint128::new(relayer_share);
+            Ok(revenue)
+        })?;
+
+        for (refunded_asset, refunded_amount) in refunded.iter_mut() {
+            if *refunded_asset == asset {
+                *refunded_amount -= Uint128::new(total_price);
+            }
         }
+        refunded.retain(|(_, refunded_amount)| !refunded_amount.is_zero());
+        price.push((asset, Uint128::new(total_price)));
     }
 
-    user_data.lock_credits += amount;
-    USERS_DATA.save(deps.storage, info.sender.clone(), &user_data)?;
+    user_data.lock_credits = lock_credits;
+    USERS_DATA.save(storage, user.clone(), &user_data)?;
 
     Ok(
         Response::default()
+        .add_messages(send_assets(user, &refunded)?)
         .add_attribute("action", "purchased credits")
         .add_attribute("new credits balance", user_data.lock_credits.to_string())
+        .add_attribute("paid", format_assets(&sent))
+        .add_attribute("price", format_assets(&price))
+        .add_attribute("refunded", format_assets(&refunded))
+    )
+}
+
+/**
+ * Split the credit revenue not withdrawn yet between the treasury recipients, callable by the admin or the treasury manager.
+ * Rounding leftovers stay in the contract until the next withdrawal.
+ */
+fn withdraw_fees(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+
+    let Some(treasury) = &state.lock_credit_settings.treasury else {
+        return standard_error("No treasury configured".to_string())
+    };
+
+    ensure!(info.sender == state.admin || info.sender == treasury.manager, ContractError::Unauthorized {});
+
+    let revenues = REVENUE.range(deps.storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>()?;
+    let mut payouts : Vec<Vec<(PaymentAsset, Uint128)>> = vec![vec![]; treasury.splits.len()];
+
+    for (key, mut revenue) in revenues {
+        let withdrawable = revenue.withdrawable();
+
+        for (split, payout) in treasury.splits.iter().zip(payouts.iter_mut()) {
+            let amount = withdrawable.multiply_ratio(split.percent as u128, 100u128);
+            if !amount.is_zero() {
+                payout.push((revenue.asset.clone(), amount));
+                revenue.withdrawn += amount;
+            }
+        }
+
+        REVENUE.save(deps.storage, key, &revenue)?;
+    }
+
+    if payouts.iter().all(|payout| payout.is_empty()) {
+        return standard_error("No fees to withdraw".to_string())
+    }
+
+    let mut response = Response::default().add_attribute("action", "withdraw fees");
+    for (split, payout) in treasury.splits.iter().zip(payouts) {
+        response = response
+            .add_messages(send_assets(&split.address, &payout)?)
+            .add_attribute(split.label.clone(), format_assets(&payout));
+    }
+
+    Ok(response)
+}
+
+/**
+ * Give free credits to a batch of users, admin only.
+ * Grants made for a campaign must fit its budget and per user cap, and are refused once it expired.
+ */
+fn grant_credits(deps: DepsMut, env: Env, info: MessageInfo, recipients: Vec<(String, u64)>, campaign_name: Option<String>) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    ensure!(state.admin == info.sender, ContractError::Unauthorized {});
+    ensure!(!recipients.is_empty(), ensure_error("No recipients".to_string()));
+
+    let recipients = recipients
+        .into_iter()
+        .map(|(recipient, credits)| Ok((deps.api.addr_validate(&recipient)?, credits)))
+        .collect::<StdResult<Vec<_>>>()?;
+    let total_granted = recipients.iter()
+        .try_fold(0u64, |total, (_, credits)| total.checked_add(*credits))
+        .ok_or_else(|| ensure_error("Too many credits granted".to_string()))?;
+
+    //Check the campaign limits before granting anything
+    let mut campaign_grants : HashMap<Addr, u64> = HashMap::new();
+    let campaign = match &campaign_name {
+        Some(name) => {
+            let mut campaign = CAMPAIGNS.may_load(deps.storage, name.clone())?
+                .ok_or_else(|| ensure_error(format!("Campaign {} not found", name)))?;
+            ensure!(campaign.expires_at.is_none_or(|expires_at| env.block.time.seconds() < expires_at), ensure_error(format!("Campaign {} expired", name)));
+            let campaign_granted = campaign.granted.checked_add(total_granted)
+                .filter(|granted| *granted <= campaign.budget)
+                .ok_or_else(|| ensure_error(format!("Campaign {} budget exceeded, {} credits left", name, campaign.budget - campaign.granted)))?;
+
+            for (user, credits) in &recipients {
+                let previous_grant = match campaign_grants.get(user) {
+                    Some(user_granted) => *user_granted,
+                    None => {
+                        let previous_grant = CAMPAIGN_GRANTS.may_load(deps.storage, (name.clone(), user.clone()))?;
+                        if previous_grant.is_none() {
+                            campaign.recipients += 1;
+                        }
+                        previous_grant.unwrap_or_default()
+                    }
+                };
+
+                let user_granted = previous_grant.checked_add(*credits)
+                    .filter(|user_granted| campaign.per_user_cap.is_none_or(|cap| *user_granted <= cap))
+                    .ok_or_else(|| ensure_error(format!("Campaign {} cap reached for {}", name, user)))?;
+                campaign_grants.insert(user.clone(), user_granted);
+            }
+
+            campaign.granted = campaign_granted;
+            Some(campaign)
+        },
+        None => None
+    };
+
+    for (user, credits) in recipients {
+        let mut user_data = USERS_DATA.may_load(deps.storage, user.clone())?.unwrap_or(UserData {
+            address : user.clone(),
+            last_lock : 0,
+            lock_credits : 0
+        });
+        user_data.lock_credits = u16::try_from(credits).ok()
+            .and_then(|credits| user_data.lock_credits.checked_add(credits))
+            .ok_or_else(|| ensure_error(format!("Too many credits granted to {}", user)))?;
+        USERS_DATA.save(deps.storage, user, &user_data)?;
+    }
+
+    let mut response = Response::default()
+        .add_attribute("action", "granted credits")
+        .add_attribute("credits", total_granted.to_string());
+
+    if let Some(campaign) = campaign {
+        for (user, user_granted) in campaign_grants {
+            CAMPAIGN_GRANTS.save(deps.storage, (campaign.name.clone(), user), &user_granted)?;
+        }
+        CAMPAIGNS.save(deps.storage, campaign.name.clone(), &campaign)?;
+
+        response = response
+            .add_attribute("campaign", campaign.name)
+            .add_attribute("campaign credits left", (campaign.budget - campaign.granted).to_string());
+    }
+
+    Ok(response)
+}
+
+//Open an airdrop campaign, admin only
+fn create_campaign(deps: DepsMut, info: MessageInfo, name: String, budget: u64, per_user_cap: Option<u64>, expires_at: Option<u64>) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    ensure!(state.admin == info.sender, ContractError::Unauthorized {});
+    ensure!(!CAMPAIGNS.has(deps.storage, name.clone()), ensure_error(format!("Campaign {} already exists", name)));
+
+    CAMPAIGNS.save(deps.storage, name.clone(), &Campaign { name : name.clone(), budget, per_user_cap, expires_at, granted : 0, recipients : 0 })?;
+
+    Ok(
+        Response::default()
+        .add_attribute("action", "created campaign")
+        .add_attribute("campaign", name)
+        .add_attribute("budget", budget.to_string())
     )
 }
 
+//Maximum number of tokens carried by a single batch packet
+const MAX_BATCH_SIZE: usize = 50;
+
+/**
+ * Take the lock credits for `locks` tokens from the user, if credits are enabled.
+ * Returns the amount of credits spent, stored in the pending request to refund a failed lock.
+ */
+fn spend_lock_credits(storage: &mut dyn Storage, state: &State, user: &Addr, locks: u16) -> Result<u16, ContractError> {
+    if !state.lock_credit_settings.enabled() {
+        return Ok(0)
+    }
+
+    let credits = state.lock_credit_settings.credit_per_lock.checked_mul(locks)
+        .ok_or_else(|| ensure_error(format!("The credits of {} locks overflow", locks)))?;
+
+    match USERS_DATA.may_load(storage, user.clone()) {
+        Ok(Some(mut data)) => {
+            if data.lock_credits < credits {
+                return Err(ensure_error("You don't have enough lock credits, please purchase more".to_string()).into())
+            }
+
+            data.lock_credits -= credits;
+            USERS_DATA.save(storage, user.clone(), &data)?;
+            Ok(credits)
+        },
+        _ => Err(ensure_error("User not found, please purchase lock credits first".to_string()).into())
+    }
+}
+
+/**
+ * Create a new request, save it as pending and prepare the IBC packet carrying it to the host.
+ * Returns the request id, the timeout and the messages to add to the response.
+ */
+fn send_request(storage: &mut dyn Storage, env: &Env, state: &State, host: &HostInfo, channel_info: &ChannelInfo, packet_type: PacketType, credits: u16) -> StdResult<(u128, IbcTimeout, Vec<IbcMsg>)> {
+    let request_id = UNIQUE_PACKETS_REQUEST_ID.load(storage)? + 1;
+    UNIQUE_PACKETS_REQUEST_ID.save(storage, &request_id)?;
+
+    let request = IbcPacketOutgoing {
+        packet_type,
+        chain_prefix : host.chain_prefix.clone(),
+        timestamp : env.block.time.seconds(),
+        request_id,
+    };
+    let (timeout, ibc_messages) = send_attempt(storage, env, state, host, channel_info, request, credits)?;
+
+    Ok((request_id, timeout, ibc_messages))
+}
+
+/**
+ * Save the request as pending and prepare a new packet carrying it, a retry sends the same request again.
+ * Every packet is traced in the request attempts, the timeout is backed off by the number of previous attempts.
+ */
+fn send_attempt(storage: &mut dyn Storage, env: &Env, state: &State, host: &HostInfo, channel_info: &ChannelInfo, request: IbcPacketOutgoing, credits: u16) -> StdResult<(IbcTimeout, Vec<IbcMsg>)> {
+    let current_time = env.block.time.seconds();
+    let request_key = (request.packet_type.user().clone(), request.request_id);
+
+    let mut attempts = REQUEST_ATTEMPTS.may_load(storage, request_key.clone())?.unwrap_or_default();
+    let timeout = packet_timeout(storage, env, &state.ibc_settings, host, attempts.len() as u32)?;
+
+    //Prepare the IBC message, encoded with the protocol agreed on the channel
+    let ibc_message : IbcMsg = IbcMsg::SendPacket {
+        channel_id : channel_info.channel_id.clone(),
+        data: protocol::encode_request(&channel_info.protocol, &request, host)?,
+        timeout: timeout.clone()
+    };
+
+    //The fee is escrowed for the next packet sent on the channel, it must precede it
+    let ibc_messages = match relayer_fee_message(storage, env, state, channel_info, &request)? {
+        Some(fee_message) => vec![fee_message, ibc_message],
+        None => vec![ibc_message]
+    };
+
+    save_pending_request(storage, &request_key.0, request_key.1, &PendingPacket {
+        packet : request,
+        channel_id : channel_info.channel_id.clone(),
+        sent_at : current_time,
+        credits,
+        status : PendingStatus::InFlight,
+        timeout : timeout.clone()
+    })?;
+
+    attempts.push(RequestAttempt {
+        sent_at : current_time,
+        channel_id : channel_info.channel_id.clone(),
+        timeout : timeout.clone(),
+        outcome : AttemptOutcome::Pending
+    });
+    REQUEST_ATTEMPTS.save(storage, request_key, &attempts)?;
+
+    Ok((timeout, ibc_messages))
+}
+
+/**
+ * Timeout of a packet, every previous attempt of the request multiplies it by the retry backoff.
+ * IBC checks the timeout height against the host chain, it is counted from the last height reported by the host with a SyncState or a pong,
+ * a report older than the max_report_age of the host is refused.
+ */
+fn packet_timeout(storage: &dyn Storage, env: &Env, ibc_settings: &IbcSettings, host: &HostInfo, previous_attempts: u32) -> StdResult<IbcTimeout> {
+    //A request retried until its backed-off timeout overflows can no longer be sent
+    let overflow = || ensure_error(format!("The timeout of attempt {} overflows", previous_attempts + 1));
+    let backoff = ibc_settings.retry_backoff.map_or(Some(1), |backoff| (backoff as u64).checked_pow(previous_attempts)).ok_or_else(overflow)?;
+
+    let timestamp = ibc_settings.timeout.checked_mul(backoff)
+        .and_then(|timeout| timeout.checked_mul(1_000_000_000))
+        .and_then(|timeout| env.block.time.nanos().checked_add(timeout))
+        .map(Timestamp::from_nanos)
+        .ok_or_else(overflow)?;
+    let block = || -> StdResult<IbcTimeoutBlock> {
+        let height_timeout = host.height_timeout
+            .as_ref()
+            .ok_or_else(|| ensure_error(format!("Height timeouts require the height_timeout of host {}", host.label)))?;
+        let host_height = HOST_HEIGHTS.may_load(storage, host.label.clone())?
+            .ok_or_else(|| ensure_error(format!("Host {} has not reported its block height yet", host.label)))?;
+        ensure!(
+            host_height.reported_at.saturating_add(height_timeout.max_report_age) >= env.block.time.seconds(),
+            ensure_error(format!("The block height reported by host {} is too old, a SyncState or a ping is needed", host.label))
+        );
+        let host_height = host_height.height;
+
+        let height = height_timeout.height_offset.checked_mul(backoff)
+            .and_then(|offset| host_height.checked_add(offset))
+            .ok_or_else(overflow)?;
+
+        Ok(IbcTimeoutBlock { revision : height_timeout.revision, height })
+    };
+
+    Ok(match ibc_settings.timeout_kind {
+        TimeoutKind::Timestamp => IbcTimeout::with_timestamp(timestamp),
+        TimeoutKind::Height => IbcTimeout::with_block(block()?),
+        TimeoutKind::Both => IbcTimeout::with_both(block()?, timestamp)
+    })
+}
+
+/**
+ * ICS-29 fee for the relayers of the next packet, paid from the relayer fee pool.
+ * Without enough funds in the pool the packet is sent without fees, unused fees are refunded by the middleware to the contract,
+ * the escrow is recorded to credit them back to the pool when the packet is settled.
+ */
+fn relayer_fee_message(storage: &mut dyn Storage, env: &Env, state: &State, channel_info: &ChannelInfo, request: &IbcPacketOutgoing) -> StdResult<Option<IbcMsg>> {
+    let Some(fee_settings) = &state.ibc_settings.relayer_fees else {
+        return Ok(None)
+    };
+
+    if !channel_info.fee_enabled {
+        return Ok(None)
+    }
+
+    //Fees are paid in the first denom with enough funds in its pool
+    let mut funded_fee = None;
+    for fee in &fee_settings.fees {
+        let total_fee = fee.receive_fee.checked_add(fee.ack_fee)?.checked_add(fee.timeout_fee)?;
+        let pool = RELAYER_FEE_POOL.may_load(storage, fee.denom.clone())?.unwrap_or_default();
+        if !total_fee.is_zero() && pool >= total_fee {
+            funded_fee = Some((fee, pool - total_fee));
+            break;
+        }
+    }
+
+    let Some((fees, pool_left)) = funded_fee else {
+        return Ok(None)
+    };
+    let denom = fees.denom.clone();
+
+    RELAYER_FEE_POOL.save(storage, denom.clone(), &pool_left)?;
+    RELAYER_FEE_ESCROWS.update(storage, (request.packet_type.user().clone(), request.request_id), |escrows| -> StdResult<_> {
+        let mut escrows = escrows.unwrap_or_default();
+        escrows.push(EscrowedFee {
+            packet : request.clone(),
+            denom : denom.clone(),
+            receive_fee : fees.receive_fee,
+            ack_fee : fees.ack_fee,
+            timeout_fee : fees.timeout_fee
+        });
+        Ok(escrows)
+    })?;
+
+    let fee_coins = |amount: Uint128| if amount.is_zero() { vec![] } else { vec![Coin::new(amount, denom.clone())] };
+
+    Ok(Some(IbcMsg::PayPacketFee {
+        port_id : format!("wasm.{}", env.contract.address),
+        channel_id : channel_info.channel_id.clone(),
+        fee : IbcFee {
+            receive_fee : fee_coins(fees.receive_fee),
+            ack_fee : fee_coins(fees.ack_fee),
+            timeout_fee : fee_coins(fees.timeout_fee)
+        },
+        relayers : vec![]
+    }))
+}
+
+/**
+ * Resolve the route shared by every token of a batch, a batch packet can only go to a single host
+ */
+fn load_batch_route(storage: &dyn Storage, state: &State, tokens: &[(String, String)]) -> Result<(HostInfo, ChannelInfo), ContractError> {
+    ensure!(!tokens.is_empty() && tokens.len() <= MAX_BATCH_SIZE, ensure_error(format!("A batch must contain between 1 and {} tokens", MAX_BATCH_SIZE)));
+
+    let mut unique_tokens = tokens.to_vec();
+    unique_tokens.sort();
+    unique_tokens.dedup();
+    ensure!(unique_tokens.len() == tokens.len(), ensure_error("The batch contains duplicated tokens".to_string()));
+
+    let (host, channel_info) = load_collection_route(storage, state, &tokens[0].0)?;
+    for (collection, _) in tokens.iter().skip(1) {
+        let (collection_host, _) = load_collection_route(storage, state, collection)?;
+        ensure!(collection_host.label == host.label, ensure_error("Every token of a batch must belong to the same host".to_string()));
+    }
+
+    Ok((host, channel_info))
+}
+
 /**
  * Init the lock procedure, 
  * 0) Basic checks
@@ -151,80 +935,35 @@ fn purchase_credits(deps: DepsMut, info: MessageInfo, amount: u16) -> Result<Res
  */
 fn init_lock_procedure(deps: DepsMut, env: Env, info: MessageInfo, message: Cw721ReceiveMsg) -> Result<Response, ContractError> {
     let state = STATE.load(deps.storage)?;
-    let channel_info = CHANNEL.load(deps.storage)?;
-
-    let is_collection_supported = state.collections_info
-            .iter()
-            .find(|collection| collection.address == info.sender.to_string())
-            .is_some();
+    let (host, channel_info) = load_collection_route(deps.storage, &state, info.sender.as_str())?;
 
-    ensure!(is_collection_supported , ensure_error("The collection is not supported.".into()));
     ensure!(channel_info.finalized, ensure_error("Can't lock, IBC channel is closed.".into()));
 
     let msg: NftReceiveMsg = from_json(&message.msg)?;
     let user = Addr::unchecked(message.sender);
+    ensure_lock_cooldown(deps.storage, &state, &env, info.sender.as_str(), &message.token_id)?;
 
-    if state.lock_credit_settings.token.is_some() {
-        match USERS_DATA.may_load(deps.storage, user.clone()) {
-            Ok(Some(mut data)) => {
-                if data.lock_credits < state.lock_credit_settings.credit_per_lock.clone() {
-                    return standard_error("You don't have enough lock credits, please purchase more".to_string())
-                } else {
-                    data.lock_credits -= state.lock_credit_settings.credit_per_lock.clone();
-                    USERS_DATA.save(deps.storage, user.clone(), &data)?;
-                }
-            },
-            Ok(None) => {
-                return standard_error("User not found, please purchase lock credits first".to_string())
-            },
-            Err(_e) => {
-                return standard_error("User not found, please purchase lock credits first".to_string())
-            }
-        }
-    }
+    let credits_spent = spend_lock_credits(deps.storage, &state, &user, 1)?;
 
     match msg {
         NftReceiveMsg::LockNft { remote_recipient } => {
-            let request_id = UNIQUE_PACKETS_REQUEST_ID.load(deps.storage)? + 1;
-
-            //Set timeout and prepare the IBC package
-            let current_time = env.block.time.seconds();
-            let timeout = current_time + state.ibc_settings.timeout;
-
-            let lock_request = IbcPacketOutgoing {
-                packet_type : PacketType::LockRequest {
-                    user : user.clone(), //Local user address
-                    token_id : message.token_id.clone(),
-                    collection : info.sender.to_string(),
-                    native_address : remote_recipient
-                },
-                chain_prefix : state.host_chain_prefix,
-                timestamp : current_time,
-                request_id : request_id,
-            };
-
-            //Prepare the IBC message
-            let ibc_message : IbcMsg = IbcMsg::SendPacket {
-                channel_id : channel_info.channel_id,
-                data: to_json_binary(&lock_request)?,
-                timeout: IbcTimeout::with_timestamp(
-                    Timestamp::from_seconds(timeout.clone())
-                )
-            };
-
             //Save the pending request and send the packet through IBC
-            PENDING_PACKETS_REQUESTS.save(deps.storage, (user, request_id), &lock_request)?;
-            UNIQUE_PACKETS_REQUEST_ID.save(deps.storage, &request_id)?;
+            let lock_request = PacketType::LockRequest {
+                user : user.clone(), //Local user address
+                token_id : message.token_id.clone(),
+                collection : info.sender.to_string(),
+                native_address : remote_recipient
+            };
+            let (_, timeout, ibc_messages) = send_request(deps.storage, &env, &state, &host, &channel_info, lock_request, credits_spent)?;
 
             let mut response = Response::new()
                 .add_attribute("lock status", "pending")
-                .add_attribute("lock timeout", format!("{}s", timeout))
+                .add_attribute("lock timeout", describe_timeout(&timeout))
                 .add_attribute("locked token id", message.token_id.clone());
 
-            #[cfg(not(test))]
-            {
-                // Only in non-test builds, add the IBC message, enables IBC testing
-                response = response.add_message(ibc_message);
+            // Only in non-test builds, add the IBC message, enables IBC testing
+            if !cfg!(test) {
+                response = response.add_messages(ibc_messages);
             }
 
             Ok(response)
@@ -233,67 +972,285 @@ fn init_lock_procedure(deps: DepsMut, env: Env, info: MessageInfo, message: Cw72
 }
 
 /**
- * Init the lock procedure, 
+ * Init the batch lock procedure, the tokens are pulled from the user wallet,
+ * the contract must be approved as operator on every collection.
+ * 0) Check that the user owns every token, and that every token goes to the same host
+ * 1) Transfer the tokens to the contract, create and save a single pending request
+ * 2) Send the IBC batch lock request to the main contract
+ */
+fn init_batch_lock_procedure(deps: DepsMut, env: Env, info: MessageInfo, tokens: Vec<(String, String)>, native_address: Option<String>) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let (host, channel_info) = load_batch_route(deps.storage, &state, &tokens)?;
+
+    ensure!(channel_info.finalized, ensure_error("Can't lock, IBC channel is closed.".into()));
+
+    let mut transfer_messages = vec![];
+    for (collection, token_id) in tokens.iter() {
+        let owner: OwnerOfResponse = deps.querier.query_wasm_smart(collection, &Cw721QueryMsg::OwnerOf { token_id: token_id.clone(), include_expired: None })?;
+        ensure!(owner.owner == info.sender.as_str(), ensure_error(format!("Token {} of {} is not owned by the sender", token_id, collection)));
+        ensure_lock_cooldown(deps.storage, &state, &env, collection, token_id)?;
+
+        transfer_messages.push(send_nft(collection.clone(), token_id.clone(), env.contract.address.to_string()));
+    }
+
+    let credits_spent = spend_lock_credits(deps.storage, &state, &info.sender, tokens.len() as u16)?;
+
+    let lock_request = PacketType::BatchLockRequest {
+        user : info.sender.clone(),
+        tokens : tokens.clone(),
+        native_address
+    };
+    let (request_id, timeout, ibc_messages) = send_request(deps.storage, &env, &state, &host, &channel_info, lock_request, credits_spent)?;
+
+    let mut response = Response::new()
+        .add_attribute("lock status", "pending")
+        .add_attribute("lock timeout", describe_timeout(&timeout))
+        .add_attribute("request id", request_id.to_string())
+        .add_attribute("locked tokens", tokens.len().to_string())
+        .add_messages(transfer_messages);
+
+    // Only in non-test builds, add the IBC message, enables IBC testing
+    if !cfg!(test) {
+        response = response.add_messages(ibc_messages);
+    }
+
+    Ok(response)
+}
+
+//A token can be locked again once the collection cooldown is elapsed since its previous lock
+fn ensure_lock_cooldown(storage: &dyn Storage, state: &State, env: &Env, collection: &str, token_id: &str) -> Result<(), ContractError> {
+    let Some(cooldown) = load_collection_info(state, collection)?.lock_cooldown else {
+        return Ok(())
+    };
+
+    if let Some(last_lock) = LAST_TOKEN_LOCKS.may_load(storage, (collection.to_string(), token_id.to_string()))? {
+        let available_at = last_lock.saturating_add(cooldown);
+        ensure!(env.block.time.seconds() >= available_at, ensure_error(format!("Token {} can't be locked again before {}", token_id, available_at)));
+    }
+
+    Ok(())
+}
+
+/**
+ * Ensure the sender locked the token, for at least the collection minimum lock duration, and no other unlock of the token is in flight,
+ * the guard is released once the request is acked or timed out.
+ * Requests on the token left recoverable by a closed channel or a timeout are dropped.
+ */
+fn prepare_token_unlock(storage: &mut dyn Storage, state: &State, env: &Env, user: &Addr, collection: &str, token_id: &str) -> Result<(), ContractError> {
+    let locked_token = LOCKED_TOKENS.may_load(storage, (collection.to_string(), token_id.to_string()))?
+        .filter(|locked_token| locked_token.owner == *user)
+        .ok_or_else(|| ensure_error("The token is not locked in the contract, or not owned by the user".to_string()))?;
+
+    ensure_min_lock_duration(state, collection, token_id, &locked_token, env.block.time.seconds())?;
+
+    let token_requests = load_token_requests(storage, user, collection, token_id)?;
+    if let Some((in_flight_request_id, _)) = token_requests.iter().find(|(_, pending)| pending.status == PendingStatus::InFlight) {
+        return Err(ensure_error(format!("An unlock of token {} is already in flight (request {})", token_id, in_flight_request_id)).into())
+    }
+
+    //The new unlock replaces the recoverable ones, batch requests are kept for their other tokens
+    for (recoverable_request_id, mut pending) in token_requests {
+        match pending.packet.packet_type.without_token(collection, token_id) {
+            Some(packet_type) => {
+                pending.packet.packet_type = packet_type;
+                save_pending_request(storage, user, recoverable_request_id, &pending)?;
+            },
+            None => remove_pending_request(storage, user, recoverable_request_id)?
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Init the unlock procedure, 
  * 0) Check if the user has an account, and checks if the requested token has been locked by him
  * 1) Create and save the pending request in the state
  * 2) Send the IBC unlock request to the main contract
  */
 fn init_unlock_procedure(deps: DepsMut, env: Env, info: MessageInfo, collection: String, token_id: String, native_address : Option<String>) -> Result<Response, ContractError> {
 
-    //Ensure the user exists and owns the token
-    let user_data = match USERS_DATA.may_load(deps.storage, info.sender.clone()) {
-        Ok(Some(data)) => data,
-        Ok(None) => return standard_error("User not found".to_string()),
-        Err(_e) => return standard_error("User not found".to_string())
-    };  
-
-    let user_collection_tokens = user_data.locked_tokens.get(&collection);
-    ensure!(user_collection_tokens.is_some_and(|locked_tokens| locked_tokens.contains(&token_id)), ensure_error("The token is not locked in the contract, or not owned by the usre".to_string()));
+    //Ensure the user exists
+    ensure!(USERS_DATA.has(deps.storage, info.sender.clone()), ensure_error("User not found".to_string()));
 
     //Load state for IBC operation
-    let channel_info = CHANNEL.load(deps.storage)?;
-    let request_id = UNIQUE_PACKETS_REQUEST_ID.load(deps.storage)? + 1;
     let state = STATE.load(deps.storage)?;
+    let (host, channel_info) = load_collection_route(deps.storage, &state, &collection)?;
 
-    //Set timeout and prepare the IBC package
-    let current_time = env.block.time.seconds();
-    let timeout = current_time + state.ibc_settings.timeout;
-
-    let unlock_request = IbcPacketOutgoing {
-        packet_type : PacketType::UnlockRequest {
-            user : user_data.address.clone(),
-            token_id : token_id.clone(),
-            collection,
-            native_address
-        },
-        chain_prefix : state.host_chain_prefix,
-        timestamp : current_time,
-        request_id : request_id,
-    };
+    ensure!(channel_info.finalized, ensure_error("Can't unlock, IBC channel is closed.".into()));
 
-    //Prepare the IBC message
-    let ibc_message : IbcMsg = IbcMsg::SendPacket {
-        channel_id : channel_info.channel_id,
-        data: to_json_binary(&unlock_request)?,
-        timeout: IbcTimeout::with_timestamp(
-            Timestamp::from_seconds(timeout.clone())
-        )
-    };
+    prepare_token_unlock(deps.storage, &state, &env, &info.sender, &collection, &token_id)?;
 
     //Save the pending request and send the packet through IBC
-    PENDING_PACKETS_REQUESTS.save(deps.storage, (user_data.address.clone(), request_id), &unlock_request)?;
-    UNIQUE_PACKETS_REQUEST_ID.save(deps.storage, &request_id)?;
+    let unlock_request = PacketType::UnlockRequest {
+        user : info.sender.clone(),
+        token_id : token_id.clone(),
+        collection,
+        native_address
+    };
+    let (_, timeout, ibc_messages) = send_request(deps.storage, &env, &state, &host, &channel_info, unlock_request, 0)?;
 
     let mut response = Response::new()
             .add_attribute("unlock status", "pending")
-            .add_attribute("unlock timeout", format!("{}s", timeout))
+            .add_attribute("unlock timeout", describe_timeout(&timeout))
             .add_attribute("unlocked token id", token_id.clone());
 
-    #[cfg(not(test))]
-    {
-        // Only in non-test builds, add the IBC message, enables IBC testing
-        response = response.add_message(ibc_message);
+    // Only in non-test builds, add the IBC message, enables IBC testing
+    if !cfg!(test) {
+        response = response.add_messages(ibc_messages);
+    }
+
+    Ok(response)
+}
+
+/**
+ * Init the batch unlock procedure, every token must have been locked by the sender and belong to the same host
+ */
+fn init_batch_unlock_procedure(deps: DepsMut, env: Env, info: MessageInfo, tokens: Vec<(String, String)>, native_address : Option<String>) -> Result<Response, ContractError> {
+    ensure!(USERS_DATA.has(deps.storage, info.sender.clone()), ensure_error("User not found".to_string()));
+
+    let state = STATE.load(deps.storage)?;
+    let (host, channel_info) = load_batch_route(deps.storage, &state, &tokens)?;
+
+    ensure!(channel_info.finalized, ensure_error("Can't unlock, IBC channel is closed.".into()));
+
+    for (collection, token_id) in tokens.iter() {
+        prepare_token_unlock(deps.storage, &state, &env, &info.sender, collection, token_id)?;
+    }
+
+    let unlock_request = PacketType::BatchUnlockRequest {
+        user : info.sender.clone(),
+        tokens : tokens.clone(),
+        native_address
+    };
+    let (request_id, timeout, ibc_messages) = send_request(deps.storage, &env, &state, &host, &channel_info, unlock_request, 0)?;
+
+    let mut response = Response::new()
+        .add_attribute("unlock status", "pending")
+        .add_attribute("unlock timeout", describe_timeout(&timeout))
+        .add_attribute("request id", request_id.to_string())
+        .add_attribute("unlocked tokens", tokens.len().to_string());
+
+    // Only in non-test builds, add the IBC message, enables IBC testing
+    if !cfg!(test) {
+        response = response.add_messages(ibc_messages);
     }
 
     Ok(response)
-}
\ No newline at end of file
+}
+
+/**
+ * Send again an unlock request that timed out, or was stranded on a closed channel, with a new packet.
+ * The request keeps its id so every attempt is traced together, tokens unlocked in the meantime are left out.
+ */
+fn retry_unlock(deps: DepsMut, env: Env, info: MessageInfo, request_id: u128) -> Result<Response, ContractError> {
+    let pending = PENDING_PACKETS_REQUESTS.may_load(deps.storage, (info.sender.clone(), request_id))?
+        .ok_or_else(|| ensure_error(format!("Request {} not found", request_id)))?;
+
+    ensure!(!pending.packet.packet_type.is_lock(), ensure_error("Only unlock requests can be retried".to_string()));
+    ensure!(pending.status != PendingStatus::InFlight, ensure_error("The request is still in flight".to_string()));
+
+    let mut tokens = vec![];
+    for (collection, token_id) in pending.packet.packet_type.tokens() {
+        if is_token_locked(deps.storage, &info.sender, &collection, &token_id)? {
+            tokens.push((collection, token_id));
+        }
+    }
+    ensure!(!tokens.is_empty(), ensure_error("Every token of the request is already unlocked".to_string()));
+
+    let state = STATE.load(deps.storage)?;
+    let (host, channel_info) = load_batch_route(deps.storage, &state, &tokens)?;
+
+    ensure!(channel_info.finalized, ensure_error("Can't unlock, IBC channel is closed.".into()));
+
+    let packet_type = match pending.packet.packet_type {
+        PacketType::BatchUnlockRequest { user, native_address, .. } => PacketType::BatchUnlockRequest { user, tokens, native_address },
+        packet_type => packet_type
+    };
+    let request = IbcPacketOutgoing {
+        packet_type,
+        chain_prefix : host.chain_prefix.clone(),
+        timestamp : env.block.time.seconds(),
+        request_id
+    };
+    let (timeout, ibc_messages) = send_attempt(deps.storage, &env, &state, &host, &channel_info, request, pending.credits)?;
+    let attempts = REQUEST_ATTEMPTS.load(deps.storage, (info.sender.clone(), request_id))?;
+
+    let mut response = Response::new()
+        .add_attribute("unlock status", "pending")
+        .add_attribute("unlock timeout", describe_timeout(&timeout))
+        .add_attribute("request id", request_id.to_string())
+        .add_attribute("attempt", attempts.len().to_string());
+
+    // Only in non-test builds, add the IBC message, enables IBC testing
+    if !cfg!(test) {
+        response = response.add_messages(ibc_messages);
+    }
+
+    Ok(response)
+}
+
+//Clear the consecutive unlock timeouts of a token, admin only
+fn reset_unlock_timeouts(deps: DepsMut, info: MessageInfo, collection: String, token_id: String, user: Addr) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    ensure!(state.admin == info.sender, ContractError::Unauthorized {});
+
+    let counter_key = (collection.clone(), token_id.clone(), user.clone());
+    let previous_count = TIMED_OUT_UNLOCK_REQUESTS.may_load(deps.storage, counter_key.clone())?.unwrap_or_default();
+    TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, counter_key);
+
+    Ok(
+        Response::new()
+        .add_attribute("action", "reset unlock timeouts")
+        .add_attribute("collection", collection)
+        .add_attribute("token_id", token_id)
+        .add_attribute("user", user.to_string())
+        .add_attribute("previous count", previous_count.to_string())
+    )
+}
+
+/**
+ * Settle the requests whose packet is long past its timeout without any ack or timeout relayed, callable by anyone, in batches of `limit`.
+ * The requests are made recoverable: unlocks can be sent again, locks can be reclaimed with an emergency unlock (even if disabled).
+ * A late ack or timeout of the packet is still applied until the request is sent again.
+ * Packets with a height only timeout are never pruned, their expiry on the host can't be known here.
+ */
+fn prune_pending(deps: DepsMut, env: Env, limit: Option<u16>) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let grace = state.ibc_settings.prune_grace.unwrap_or(state.ibc_settings.timeout);
+    let current_time = env.block.time.seconds();
+    let limit = limit.unwrap_or(10) as usize;
+
+    //Requests in flight come by expiry, the scan stops at the first one not expired yet
+    let expired_requests = PENDING_REQUEST_TIMEOUTS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take_while(|res| res.as_ref().map_or(true, |(timeout, _, _)| timeout.saturating_add(grace) <= current_time))
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "prune pending")
+        .add_attribute("pruned requests", expired_requests.len().to_string());
+
+    for (_, user, request_id) in expired_requests {
+        let mut pending = PENDING_PACKETS_REQUESTS.load(deps.storage, (user.clone(), request_id))?;
+        record_attempt_outcome(deps.storage, &user, request_id, AttemptOutcome::Pruned)?;
+
+        pending.status = PendingStatus::Recoverable;
+        save_pending_request(deps.storage, &user, request_id, &pending)?;
+
+        for (collection, token_id) in pending.packet.packet_type.tokens() {
+            record_history(deps.storage, current_time, &user, &collection, &token_id, Some(request_id), HistoryAction::Pruned)?;
+        }
+
+        response = response.add_event(
+            Event::new("pruned_pending_request")
+            .add_attribute("user", user.to_string())
+            .add_attribute("request_id", request_id.to_string())
+            .add_attribute("packet_type", pending.packet.packet_type.to_string())
+            .add_attribute("channel_id", pending.channel_id)
+        );
+    }
+
+    Ok(response)
+}
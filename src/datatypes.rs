@@ -1,45 +1,254 @@
 use std::{collections::HashMap, fmt};
 
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Binary, Coin};
+use cosmwasm_std::{Addr, Binary, IbcTimeout, StdResult, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::helpers::ensure_error;
+
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct State {
     pub collections_info : Vec<CollectionInfo>,
     pub admin : Addr,
     pub ibc_settings : IbcSettings,
-    pub host_chain_prefix : String,
+    pub hosts : Vec<HostInfo>,
     pub lock_credit_settings : LockCreditSettings
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct LockCreditSettings {
-    pub token : Option<Coin>,
-    pub credit_per_lock : u16
+    pub prices : Vec<CreditPrice>, //Price of a credit in every accepted asset, credits are disabled when empty
+    pub credit_per_lock : u16,
+    #[serde(default)]
+    pub treasury : Option<TreasurySettings>, //Recipients of the credit revenue, None keeps the revenue in the contract
+    #[serde(default)]
+    pub promotions : Vec<Promotion>
+}
+
+impl LockCreditSettings {
+    pub fn enabled(&self) -> bool {
+        !self.prices.is_empty()
+    }
+
+    pub fn accepts(&self, asset : &PaymentAsset) -> bool {
+        self.prices.iter().any(|price| price.asset == *asset)
+    }
+
+    //Cost of `amount` credits at `now`, the tier reached by the quantity sets the price of a credit and the best running promotion discounts the total
+    pub fn quote(&self, asset : &PaymentAsset, amount : u16, now : u64) -> StdResult<CreditQuote> {
+        let price = self.prices.iter().find(|price| price.asset == *asset)
+            .ok_or_else(|| ensure_error(format!("{} is not accepted for credits", asset)))?;
+
+        let unit_price = price.tiers
+            .iter()
+            .filter(|tier| amount >= tier.min_amount)
+            .max_by_key(|tier| tier.min_amount)
+            .map_or(price.amount, |tier| tier.amount);
+
+        let discount = self.promotions
+            .iter()
+            .filter(|promotion| promotion.start <= now && now < promotion.end)
+            .map(|promotion| promotion.discount)
+            .max()
+            .unwrap_or_default();
+
+        let cost = unit_price
+            .checked_mul(Uint128::from(amount))
+            .map_err(|_| ensure_error(format!("The cost of {} credits overflows", amount)))?
+            .multiply_ratio(100 - discount as u128, 100u128);
+
+        Ok(CreditQuote { asset : asset.clone(), unit_price, discount, cost })
+    }
+}
+
+//Percentage taken off every credit purchase between start (included) and end (excluded), in seconds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Promotion {
+    pub start : u64,
+    pub end : u64,
+    pub discount : u8
+}
+
+#[cw_serde]
+pub struct CreditQuote {
+    pub asset : PaymentAsset,
+    pub unit_price : Uint128, //Price of a credit in the tier of the quantity, before the promotion
+    pub discount : u8,
+    pub cost : Uint128
+}
+
+//The revenue left after the relayer share is split between the recipients on every ExecuteMsg::WithdrawFees
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct TreasurySettings {
+    pub manager : Addr, //Treasury role, can withdraw the fees along with the admin
+    pub splits : Vec<TreasurySplit> //Percentages must add up to 100
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct TreasurySplit {
+    pub label : String,
+    pub address : Addr,
+    pub percent : u8
+}
+
+//Credit sales paid in an asset, the relayer share stays in the contract to pay the relayer fees
+#[cw_serde]
+pub struct AssetRevenue {
+    pub asset : PaymentAsset,
+    pub collected : Uint128,
+    pub relayer_share : Uint128,
+    pub withdrawn : Uint128
+}
+
+impl AssetRevenue {
+    pub fn withdrawable(&self) -> Uint128 {
+        self.collected - self.relayer_share - self.withdrawn
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct CreditPrice {
+    pub asset : PaymentAsset,
+    pub amount : Uint128,
+    #[serde(default)]
+    pub tiers : Vec<PriceTier> //Bulk prices, replace `amount` when buying at least `min_amount` credits at once
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PriceTier {
+    pub min_amount : u16,
+    pub amount : Uint128
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub enum PaymentAsset {
+    Native { denom : String },
+    Cw20 { address : String }
+}
+
+impl fmt::Display for PaymentAsset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaymentAsset::Native { denom } => write!(f, "{}", denom),
+            PaymentAsset::Cw20 { address } => write!(f, "cw20:{}", address),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct IbcSettings {
     pub timeout : u64,
-    pub max_timeouts : u8
+    pub max_timeouts : u8,
+    #[serde(default)]
+    pub emergency_unlock_grace : Option<u64>, //Seconds without channel or ack before a locker can reclaim the NFT, None disables it
+    #[serde(default)]
+    pub relayer_fees : Option<RelayerFeeSettings>, //ICS-29 fees attached to every packet sent on a fee enabled channel, None disables them
+    #[serde(default)]
+    pub timeout_kind : TimeoutKind,
+    #[serde(default)]
+    pub ping_interval : Option<u64>, //Minimum seconds between two heartbeats on a channel, None disables ExecuteMsg::Ping
+    #[serde(default)]
+    pub retry_backoff : Option<u8>, //Timeout multiplier applied on every retry of a request, None keeps the same timeout
+    #[serde(default)]
+    pub prune_grace : Option<u64> //Seconds past the timeout of a request before ExecuteMsg::PrunePending can settle it, defaults to `timeout`
+}
+
+//What times out a packet, the timestamp (`timeout` seconds), the host block height or whichever comes first
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutKind {
+    #[default]
+    Timestamp,
+    Height,
+    Both
+}
+
+//The timeout height is the last height reported by the host plus the offset, the offset must also cover the blocks produced since the report.
+//Height timeouts need a live height feed from the host (SyncState packets or pong acks), packets can't be sent without a recent report
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct HeightTimeout {
+    pub revision : u64,
+    pub height_offset : u64,
+    pub max_report_age : u64 //Seconds a reported height can be used for, older reports are refused
+}
+
+//Last block height reported by a host, with the time it was received
+#[cw_serde]
+pub struct HostHeight {
+    pub height : u64,
+    pub reported_at : u64
+}
+
+//Fees are paid from the share of every credit purchase set aside for relayers, each denom from its own pool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RelayerFeeSettings {
+    pub fees : Vec<RelayerFee>, //A packet is paid in the first denom with enough funds in its pool
+    pub revenue_share : u8 //Percentage of the credit revenue moved to the relayer fee pool, for the denoms with a fee only
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RelayerFee {
+    pub denom : String,
+    pub receive_fee : Uint128,
+    pub ack_fee : Uint128,
+    pub timeout_fee : Uint128
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct CollectionInfo {
-    pub address : String
+    pub address : String,
+    pub host : String, //Label of the host the locks of this collection are sent to
+    #[serde(default)]
+    pub min_lock_duration : Option<u64>, //Seconds a token must stay locked before it can be unlocked
+    #[serde(default)]
+    pub lock_cooldown : Option<u64> //Seconds between two locks of the same token
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct HostInfo {
+    pub label : String, //e.g osmosis, juno
+    pub chain_prefix : String, //e.g orai, osmo, juno, inj etc...
+    #[serde(default)]
+    pub height_timeout : Option<HeightTimeout> //Required by the height and hybrid timeouts
+}
+
+//Account of a user, the tokens locked by the user live in the LOCKED_TOKENS ledger
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct UserData {
+    pub address : Addr,
+    pub last_lock : u64,
+    pub lock_credits : u16
+}
+
+//User account along with its locked tokens, grouped by collection
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct UserDataResponse {
     pub address : Addr,
     pub locked_tokens : HashMap<String, Vec<String>>,
     pub last_lock : u64,
     pub lock_credits : u16
 }
 
+//Entry of the token ledger, keyed by (collection, token_id)
+#[cw_serde]
+pub struct LockedToken {
+    pub owner : Addr,
+    pub locked_at : u64,
+    pub native_address : Option<String> //Receiver of the token on the host, if not derived from the owner address
+}
+
+//Lock of a token as seen from the token alone, for marketplaces and game servers
+#[cw_serde]
+pub struct TokenLockResponse {
+    pub owner : Option<Addr>,
+    pub locked_at : Option<u64>,
+    pub status : TokenStatus,
+    pub pending_request_id : Option<u128>,
+    pub host_address : Option<String>
+}
+
 #[cw_serde]
 pub struct IbcPacketOutgoing {
     pub request_id : u128,
@@ -48,6 +257,116 @@ pub struct IbcPacketOutgoing {
     pub packet_type : PacketType
 }
 
+//Local record of a packet sent to the host, kept until the packet is settled
+#[cw_serde]
+pub struct PendingPacket {
+    pub packet : IbcPacketOutgoing,
+    pub channel_id : String,
+    pub sent_at : u64,
+    pub credits : u16, //Lock credits spent for the request, refunded if the lock fails
+    pub status : PendingStatus,
+    pub timeout : IbcTimeout //Timeout the packet was sent with
+}
+
+//ICS-29 fee escrowed for a packet, the middleware refunds to the contract the part no relayer earned
+#[cw_serde]
+pub struct EscrowedFee {
+    pub packet : IbcPacketOutgoing,
+    pub denom : String,
+    pub receive_fee : Uint128,
+    pub ack_fee : Uint128,
+    pub timeout_fee : Uint128
+}
+
+#[cw_serde]
+pub enum PendingStatus {
+    InFlight,
+    Recoverable, //The channel was closed before the packet was settled, the request can be sent again
+    TimedOut //The unlock packet timed out, the request can be sent again with ExecuteMsg::RetryUnlock
+}
+
+//A packet sent for a request, every retry of the request adds an attempt with the same request id
+#[cw_serde]
+pub struct RequestAttempt {
+    pub sent_at : u64,
+    pub channel_id : String,
+    pub timeout : IbcTimeout,
+    pub outcome : AttemptOutcome
+}
+
+#[cw_serde]
+pub enum AttemptOutcome {
+    Pending,
+    Acknowledged,
+    TimedOut,
+    ChannelClosed,
+    Pruned, //No ack nor timeout came back long after the timeout, the request was made recoverable
+    InvalidAck //The host answered with an ack that doesn't settle the request, the request was made recoverable
+}
+
+//What happened to a token, recorded in the history once settled
+#[cw_serde]
+pub enum HistoryAction {
+    Lock,
+    LockFailed { reason : String },
+    LockTimedOut,
+    Unlock,
+    UnlockFailed { reason : String },
+    UnlockTimedOut,
+    ForceUnlock { reason : String },
+    EmergencyUnlock,
+    Pruned //No ack nor timeout came back for the request, it was made recoverable
+}
+
+//Airdrop of free credits, every grant made for the campaign is counted against its budget
+#[cw_serde]
+pub struct Campaign {
+    pub name : String,
+    pub budget : u64,
+    pub per_user_cap : Option<u64>, //Credits a single user can receive from the campaign, None for no cap
+    pub expires_at : Option<u64>, //Seconds, grants are refused from this time on
+    pub granted : u64,
+    pub recipients : u32
+}
+
+//Entry of the append-only history, keyed by a global sequence
+#[cw_serde]
+pub struct HistoryEntry {
+    pub sequence : u64,
+    pub user : Addr,
+    pub collection : String,
+    pub token_id : String,
+    pub request_id : Option<u128>, //None for the actions initiated by the host
+    pub action : HistoryAction,
+    pub timestamp : u64
+}
+
+//Status of a token, from the pending request targeting it or from the token ledger
+#[cw_serde]
+pub enum TokenStatus {
+    Unlocked,
+    LockPending,
+    LockRecoverable, //The lock request was pruned or answered with an invalid ack, the token can be reclaimed with an emergency unlock
+    Locked,
+    UnlockPending,
+    UnlockTimedOut { count : u8 }, //The last unlock packet timed out, the request can be retried
+    EmergencyReleased
+}
+
+#[cw_serde]
+pub struct EmergencyRelease {
+    pub user : Addr,
+    pub released_at : u64,
+    pub last_request_id : u128 //Last request id issued when the token was released, the requests up to it were sent before the release
+}
+
+#[cw_serde]
+pub struct IbcPacketIncoming {
+    pub request_id : u128,
+    pub timestamp : u64,
+    pub packet_type : IncomingPacketType
+}
+
 #[cw_serde]
 pub enum AckMessage {
     Error {
@@ -55,9 +374,29 @@ pub enum AckMessage {
     },
     Success {
 
+    },
+    UserState {
+        user : Addr,
+        locked_tokens : HashMap<String, Vec<String>>,
+        lock_credits : u16
+    },
+    //Per token outcome of a batch request, tokens without an error succeeded
+    BatchResult {
+        results : Vec<BatchItemResult>
+    },
+    //Answer to a ping, with the current block height of the host for the height timeouts
+    Pong {
+        host_height : u64
     }
 }
 
+#[cw_serde]
+pub struct BatchItemResult {
+    pub collection : String,
+    pub token_id : String,
+    pub error : Option<String>
+}
+
 #[cw_serde]
 pub enum PacketType {
     LockRequest { 
@@ -71,6 +410,40 @@ pub enum PacketType {
         token_id : String,
         collection : String,
         native_address : Option<String>
+    },
+    BatchLockRequest {
+        user : Addr,
+        tokens : Vec<(String, String)>, //(collection, token_id)
+        native_address : Option<String>
+    },
+    BatchUnlockRequest {
+        user : Addr,
+        tokens : Vec<(String, String)>,
+        native_address : Option<String>
+    },
+    //Heartbeat, only its ack matters, no pending record is kept
+    Ping {
+        sender : Addr
+    }
+}
+
+//Commands sent by the host (main) contract to the satellite
+#[cw_serde]
+pub enum IncomingPacketType {
+    ForceUnlock {
+        user : Addr,
+        token_id : String,
+        collection : String,
+        reason : Option<String>
+    },
+    GrantCredits {
+        user : Addr,
+        amount : u16
+    },
+    SyncState {
+        user : Addr,
+        #[serde(default)]
+        host_height : Option<u64> //Current block height of the host, recorded for the height timeouts
     }
 }
 
@@ -86,6 +459,77 @@ impl fmt::Display for PacketType {
        match self {
            PacketType::LockRequest { .. }  => write!(f, "lock_request"),
            PacketType::UnlockRequest { .. } => write!(f, "unlock_request"),
+           PacketType::BatchLockRequest { .. } => write!(f, "batch_lock_request"),
+           PacketType::BatchUnlockRequest { .. } => write!(f, "batch_unlock_request"),
+           PacketType::Ping { .. } => write!(f, "ping"),
+       }
+    }
+}
+
+impl PacketType {
+    pub fn user(&self) -> &Addr {
+        match self {
+            PacketType::LockRequest { user, .. } |
+            PacketType::UnlockRequest { user, .. } |
+            PacketType::BatchLockRequest { user, .. } |
+            PacketType::BatchUnlockRequest { user, .. } => user,
+            PacketType::Ping { sender } => sender,
+        }
+    }
+
+    //(collection, token_id) pairs targeted by the packet
+    pub fn tokens(&self) -> Vec<(String, String)> {
+        match self {
+            PacketType::LockRequest { collection, token_id, .. } |
+            PacketType::UnlockRequest { collection, token_id, .. } => vec![(collection.clone(), token_id.clone())],
+            PacketType::BatchLockRequest { tokens, .. } |
+            PacketType::BatchUnlockRequest { tokens, .. } => tokens.clone(),
+            PacketType::Ping { .. } => vec![],
+        }
+    }
+
+    pub fn concerns_token(&self, collection : &str, token_id : &str) -> bool {
+        self.tokens().iter().any(|(packet_collection, packet_token_id)| packet_collection == collection && packet_token_id == token_id)
+    }
+
+    pub fn native_address(&self) -> Option<&String> {
+        match self {
+            PacketType::LockRequest { native_address, .. } |
+            PacketType::UnlockRequest { native_address, .. } |
+            PacketType::BatchLockRequest { native_address, .. } |
+            PacketType::BatchUnlockRequest { native_address, .. } => native_address.as_ref(),
+            PacketType::Ping { .. } => None,
+        }
+    }
+
+    //Same packet without the token, None when it was the only one
+    pub fn without_token(&self, collection : &str, token_id : &str) -> Option<PacketType> {
+        let keep = |(packet_collection, packet_token_id) : &(String, String)| packet_collection != collection || packet_token_id != token_id;
+
+        match self {
+            PacketType::BatchLockRequest { user, tokens, native_address } => {
+                let tokens : Vec<_> = tokens.iter().filter(|token| keep(token)).cloned().collect();
+                (!tokens.is_empty()).then(|| PacketType::BatchLockRequest { user : user.clone(), tokens, native_address : native_address.clone() })
+            },
+            PacketType::BatchUnlockRequest { user, tokens, native_address } => {
+                let tokens : Vec<_> = tokens.iter().filter(|token| keep(token)).cloned().collect();
+                (!tokens.is_empty()).then(|| PacketType::BatchUnlockRequest { user : user.clone(), tokens, native_address : native_address.clone() })
+            },
+            packet_type => (!packet_type.concerns_token(collection, token_id)).then(|| packet_type.clone())
+        }
+    }
+
+    pub fn is_lock(&self) -> bool {
+        matches!(self, PacketType::LockRequest { .. } | PacketType::BatchLockRequest { .. })
+    }
+}
+
+impl fmt::Display for IncomingPacketType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+       match self {
+           IncomingPacketType::ForceUnlock { .. } => write!(f, "force_unlock"),
+           IncomingPacketType::GrantCredits { .. } => write!(f, "grant_credits"),
+           IncomingPacketType::SyncState { .. } => write!(f, "sync_state"),
        }
     }
 }
@@ -97,9 +541,41 @@ pub enum NftReceiveMsg {
     }
 }
 
+//Message attached to a cw20 Send to the satellite
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub enum Cw20HookMsg {
+    GetCredits {
+        amount : u16
+    }
+}
+
 #[cw_serde]
 pub struct ChannelInfo {
     pub channel_id: String,
     pub finalized: bool,
-    pub opened_at : u64
+    pub opened_at : u64,
+    pub host : Option<String>, //Host label, set by the admin once the channel is bound
+    pub closed_at : Option<u64>,
+    pub protocol : ChannelProtocol,
+    #[serde(default)]
+    pub fee_enabled : bool //The channel runs through the ICS-29 fee middleware, set by the admin when binding it
+}
+
+//Relayer liveness seen from the satellite, every successful ack coming back proves the relayer and the host are alive
+#[cw_serde]
+#[derive(Default)]
+pub struct ChannelHealth {
+    pub last_ping_at : Option<u64>,
+    pub last_ack_at : Option<u64>,
+    pub last_timeout_at : Option<u64>,
+    #[serde(default)]
+    pub last_error_ack_at : Option<u64> //Error acks are relayed but show the host failing, they don't count as liveness
+}
+
+//Packet format spoken on a channel, chosen from the version agreed during the handshake
+#[cw_serde]
+pub enum ChannelProtocol {
+    SatelliteV1,
+    SatelliteV2,
+    Ics721
 }
\ No newline at end of file
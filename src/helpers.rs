@@ -1,18 +1,22 @@
-use cosmwasm_std::{to_json_binary, CosmosMsg, Response, StdError, WasmMsg};
+use std::collections::HashMap;
+
+use cosmwasm_std::{to_json_binary, Addr, BankMsg, Coin, CosmosMsg, Empty, IbcTimeout, Order, Response, StdError, StdResult, Storage, Uint128, WasmMsg};
+use cw20::Cw20ExecuteMsg;
+use bech32_addr_converter::converter::any_addr_to_prefix_addr;
 use cw721::Cw721ExecuteMsg;
-use crate::ContractError;
+use crate::{datatypes::{AttemptOutcome, ChannelInfo, CollectionInfo, HistoryAction, HistoryEntry, HostHeight, HostInfo, IbcPacketOutgoing, LockedToken, PaymentAsset, PendingPacket, PendingStatus, State, TokenStatus, UserData, UserDataResponse}, state::{CHANNELS, CHANNELS_HEALTH, CHANNEL_PENDING_REQUESTS, EMERGENCY_RELEASES, HISTORY, HISTORY_SEQUENCE, HOST_CHANNELS, HOST_HEIGHTS, LAST_TOKEN_LOCKS, PENDING_PACKETS_REQUESTS, PENDING_REQUEST_TIMEOUTS, LOCKED_TOKENS, RELAYER_FEE_ESCROWS, RELAYER_FEE_POOL, REQUEST_ATTEMPTS, TIMED_OUT_UNLOCK_REQUESTS, TOKEN_PENDING_REQUESTS, USERS_DATA}, ContractError};
 
 pub(crate) fn standard_error(message : String) -> Result<Response, ContractError> {
-    return Err(ContractError::Std(StdError::generic_err(format!("error : ||{}||", message.clone()))));
+    Err(ContractError::Std(StdError::generic_err(format!("error : ||{}||", message.clone()))))
 }
 
 pub(crate) fn ensure_error(message : String) -> StdError {
-    return StdError::generic_err(format!("error : ||{}||", message.clone()));
+    StdError::generic_err(format!("error : ||{}||", message.clone()))
 }
 
 pub(crate) fn send_nft(collection : String, token_id : String, recipient : String) -> CosmosMsg {
 
-    let msg = CosmosMsg::Wasm(WasmMsg::Execute {
+    CosmosMsg::Wasm(WasmMsg::Execute {
         contract_addr: collection.clone(),
         msg: to_json_binary(
             &(Cw721ExecuteMsg::TransferNft {
@@ -21,6 +25,393 @@ pub(crate) fn send_nft(collection : String, token_id : String, recipient : Strin
             }),
         ).unwrap(),
         funds: vec![],
-    });
-    return msg;
+    })
+}
+
+/**
+ * Send back native coins with a single bank message, and every cw20 with a transfer
+ */
+pub(crate) fn send_assets(recipient : &Addr, assets : &[(PaymentAsset, Uint128)]) -> StdResult<Vec<CosmosMsg>> {
+    let mut messages = vec![];
+    let mut coins = vec![];
+
+    for (asset, amount) in assets {
+        match asset {
+            PaymentAsset::Native { denom } => coins.push(Coin::new(*amount, denom.clone())),
+            PaymentAsset::Cw20 { address } => messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr : address.clone(),
+                msg : to_json_binary(&Cw20ExecuteMsg::Transfer { recipient : recipient.to_string(), amount : *amount })?,
+                funds : vec![]
+            }))
+        }
+    }
+
+    if !coins.is_empty() {
+        //The bank module expects the coins sorted by denom
+        coins.sort_by(|a, b| a.denom.cmp(&b.denom));
+        messages.insert(0, CosmosMsg::Bank(BankMsg::Send { to_address : recipient.to_string(), amount : coins }));
+    }
+
+    Ok(messages)
+}
+
+pub(crate) fn format_assets(assets : &[(PaymentAsset, Uint128)]) -> String {
+    if assets.is_empty() {
+        return "0".to_string()
+    }
+
+    assets.iter().map(|(asset, amount)| format!("{}{}", amount, asset)).collect::<Vec<_>>().join(",")
+}
+
+/**
+ * Resolve the host a collection belongs to, and the channel bound to that host
+ */
+pub(crate) fn load_collection_info<'a>(state : &'a State, collection : &str) -> StdResult<&'a CollectionInfo> {
+    state.collections_info
+        .iter()
+        .find(|collection_info| collection_info.address == collection)
+        .ok_or_else(|| ensure_error("The collection is not supported.".to_string()))
+}
+
+//A locked token can't leave the contract before the minimum lock duration of its collection
+pub(crate) fn ensure_min_lock_duration(state : &State, collection : &str, token_id : &str, locked_token : &LockedToken, now : u64) -> StdResult<()> {
+    if let Some(min_lock_duration) = load_collection_info(state, collection)?.min_lock_duration {
+        let unlockable_at = locked_token.locked_at.saturating_add(min_lock_duration);
+        if now < unlockable_at {
+            return Err(ensure_error(format!("Token {} can't be unlocked before {}", token_id, unlockable_at)))
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn load_collection_route(storage : &dyn Storage, state : &State, collection : &str) -> StdResult<(HostInfo, ChannelInfo)> {
+    let collection_info = load_collection_info(state, collection)?;
+
+    load_host_route(storage, state, &collection_info.host)
+}
+
+pub(crate) fn load_host_route(storage : &dyn Storage, state : &State, host_label : &str) -> StdResult<(HostInfo, ChannelInfo)> {
+    let host = state.hosts
+        .iter()
+        .find(|host| host.label == host_label)
+        .ok_or_else(|| ensure_error(format!("Host {} not found", host_label)))?;
+
+    let channel_id = HOST_CHANNELS.may_load(storage, host.label.clone())?
+        .ok_or_else(|| ensure_error(format!("No IBC channel bound to host {}", host.label)))?;
+
+    Ok((host.clone(), CHANNELS.load(storage, channel_id)?))
+}
+
+//Address of the user on the host, the native address if provided or the user address with the host chain prefix
+pub(crate) fn host_address(host : &HostInfo, user : &Addr, native_address : Option<&String>) -> StdResult<String> {
+    match native_address {
+        Some(native_address) => Ok(native_address.clone()),
+        None => any_addr_to_prefix_addr(user.to_string(), &host.chain_prefix)
+            .map_err(|err| StdError::generic_err(format!("{:?}", err)))
+    }
+}
+
+//Every pending request of the user targeting the given token
+pub(crate) fn load_token_requests(storage : &dyn Storage, user : &Addr, collection : &str, token_id : &str) -> StdResult<Vec<(u128, PendingPacket)>> {
+    let user_requests = PENDING_PACKETS_REQUESTS
+        .prefix(user.clone())
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(
+        user_requests
+        .into_iter()
+        .filter(|(_, pending)| pending.packet.packet_type.concerns_token(collection, token_id))
+        .collect()
+    )
+}
+
+//Save a pending request, every token it targets points to it, and its channel and expiry while it is in flight
+pub(crate) fn save_pending_request(storage : &mut dyn Storage, user : &Addr, request_id : u128, pending : &PendingPacket) -> StdResult<()> {
+    unindex_pending_request(storage, user, request_id)?;
+
+    for token in pending.packet.packet_type.tokens() {
+        TOKEN_PENDING_REQUESTS.save(storage, token, &(user.clone(), request_id))?;
+    }
+
+    if pending.status == PendingStatus::InFlight {
+        CHANNEL_PENDING_REQUESTS.save(storage, (pending.channel_id.clone(), user.clone(), request_id), &Empty {})?;
+        if let Some(timestamp) = pending.timeout.timestamp() {
+            PENDING_REQUEST_TIMEOUTS.save(storage, (timestamp.seconds(), user.clone(), request_id), &Empty {})?;
+        }
+    }
+
+    PENDING_PACKETS_REQUESTS.save(storage, (user.clone(), request_id), pending)
+}
+
+pub(crate) fn remove_pending_request(storage : &mut dyn Storage, user : &Addr, request_id : u128) -> StdResult<()> {
+    unindex_pending_request(storage, user, request_id)?;
+    PENDING_PACKETS_REQUESTS.remove(storage, (user.clone(), request_id));
+    Ok(())
+}
+
+//Tokens pointing to a newer request are left untouched
+fn unindex_pending_request(storage : &mut dyn Storage, user : &Addr, request_id : u128) -> StdResult<()> {
+    let Some(pending) = PENDING_PACKETS_REQUESTS.may_load(storage, (user.clone(), request_id))? else {
+        return Ok(())
+    };
+
+    for token in pending.packet.packet_type.tokens() {
+        if TOKEN_PENDING_REQUESTS.may_load(storage, token.clone())? == Some((user.clone(), request_id)) {
+            TOKEN_PENDING_REQUESTS.remove(storage, token);
+        }
+    }
+
+    CHANNEL_PENDING_REQUESTS.remove(storage, (pending.channel_id, user.clone(), request_id));
+    if let Some(timestamp) = pending.timeout.timestamp() {
+        PENDING_REQUEST_TIMEOUTS.remove(storage, (timestamp.seconds(), user.clone(), request_id));
+    }
+
+    Ok(())
+}
+
+/**
+ * Status of a token along with the user it concerns.
+ * A pending request on the token takes precedence over the ledger, the ledger over the last emergency release.
+ */
+pub(crate) fn load_token_status(storage : &dyn Storage, collection : &str, token_id : &str) -> StdResult<(TokenStatus, Option<Addr>)> {
+    let token_key = (collection.to_string(), token_id.to_string());
+
+    if let Some((user, request_id)) = TOKEN_PENDING_REQUESTS.may_load(storage, token_key.clone())? {
+        let pending = PENDING_PACKETS_REQUESTS.load(storage, (user.clone(), request_id))?;
+
+        let status = match (pending.packet.packet_type.is_lock(), pending.status) {
+            (true, PendingStatus::Recoverable) => TokenStatus::LockRecoverable,
+            (true, _) => TokenStatus::LockPending,
+            (false, PendingStatus::TimedOut) => TokenStatus::UnlockTimedOut {
+                count : TIMED_OUT_UNLOCK_REQUESTS.may_load(storage, (collection.to_string(), token_id.to_string(), user.clone()))?.unwrap_or_default()
+            },
+            //The unlock was stranded, the token stays locked until it is sent again
+            (false, PendingStatus::Recoverable) => TokenStatus::Locked,
+            (false, PendingStatus::InFlight) => TokenStatus::UnlockPending
+        };
+
+        return Ok((status, Some(user)))
+    }
+
+    if let Some(locked_token) = LOCKED_TOKENS.may_load(storage, token_key.clone())? {
+        return Ok((TokenStatus::Locked, Some(locked_token.owner)))
+    }
+
+    //A lock acknowledged since the release supersedes it, the token went through a regular unlock afterwards
+    let last_lock = LAST_TOKEN_LOCKS.may_load(storage, token_key.clone())?;
+    Ok(match EMERGENCY_RELEASES.may_load(storage, token_key)? {
+        Some(release) if last_lock.is_none_or(|locked_at| locked_at < release.released_at) => (TokenStatus::EmergencyReleased, Some(release.user)),
+        _ => (TokenStatus::Unlocked, None)
+    })
+}
+
+//Append an entry to the token history
+pub(crate) fn record_history(storage : &mut dyn Storage, timestamp : u64, user : &Addr, collection : &str, token_id : &str, request_id : Option<u128>, action : HistoryAction) -> StdResult<()> {
+    let sequence = HISTORY_SEQUENCE.may_load(storage)?.unwrap_or_default() + 1;
+    HISTORY_SEQUENCE.save(storage, &sequence)?;
+
+    HISTORY.save(storage, sequence, &HistoryEntry {
+        sequence,
+        user : user.clone(),
+        collection : collection.to_string(),
+        token_id : token_id.to_string(),
+        request_id,
+        action,
+        timestamp
+    })
+}
+
+//Give back the lock credits spent for a lock that didn't go through
+pub(crate) fn refund_credits(storage : &mut dyn Storage, user : &Addr, credits : u16) -> StdResult<()> {
+    if credits == 0 {
+        return Ok(())
+    }
+
+    let mut user_data = USERS_DATA.load(storage, user.clone())?;
+    user_data.lock_credits = user_data.lock_credits.saturating_add(credits);
+    USERS_DATA.save(storage, user.clone(), &user_data)
+}
+
+/**
+ * Time since the host of the collection has no open channel, None if the host is reachable.
+ * A host that never had a channel is not reported as disconnected, no token was ever locked through it.
+ */
+pub(crate) fn load_host_disconnected_since(storage : &dyn Storage, state : &State, collection : &str) -> StdResult<Option<u64>> {
+    let host = state.collections_info
+        .iter()
+        .find(|collection_info| collection_info.address == collection)
+        .map(|collection_info| collection_info.host.clone())
+        .ok_or_else(|| ensure_error("The collection is not supported.".to_string()))?;
+
+    if let Some(channel_id) = HOST_CHANNELS.may_load(storage, host.clone())? {
+        if CHANNELS.load(storage, channel_id)?.finalized {
+            return Ok(None)
+        }
+    }
+
+    let last_closed_at = CHANNELS
+        .range(storage, None, None, Order::Ascending)
+        .filter_map(|res| match res {
+            Ok((_, channel_info)) if channel_info.host.as_ref() == Some(&host) => channel_info.closed_at.map(Ok),
+            Ok(_) => None,
+            Err(err) => Some(Err(err))
+        })
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .max();
+
+    Ok(last_closed_at)
+}
+
+pub(crate) fn is_token_locked(storage : &dyn Storage, user : &Addr, collection : &str, token_id : &str) -> StdResult<bool> {
+    Ok(
+        LOCKED_TOKENS.may_load(storage, (collection.to_string(), token_id.to_string()))?
+        .is_some_and(|locked_token| locked_token.owner == *user)
+    )
+}
+
+//Tokens locked by the user, grouped by collection
+pub(crate) fn load_user_locked_tokens(storage : &dyn Storage, user : &Addr) -> StdResult<HashMap<String, Vec<String>>> {
+    let mut locked_tokens : HashMap<String, Vec<String>> = HashMap::new();
+
+    for entry in LOCKED_TOKENS.idx.owner.prefix(user.clone()).keys(storage, None, None, Order::Ascending) {
+        let (collection, token_id) = entry?;
+        locked_tokens.entry(collection).or_default().push(token_id);
+    }
+
+    Ok(locked_tokens)
+}
+
+pub(crate) fn user_data_response(storage : &dyn Storage, user_data : UserData) -> StdResult<UserDataResponse> {
+    Ok(UserDataResponse {
+        locked_tokens : load_user_locked_tokens(storage, &user_data.address)?,
+        address : user_data.address,
+        last_lock : user_data.last_lock,
+        lock_credits : user_data.lock_credits
+    })
+}
+
+//Outcome of the last packet sent for the request
+pub(crate) fn record_attempt_outcome(storage : &mut dyn Storage, user : &Addr, request_id : u128, outcome : AttemptOutcome) -> StdResult<()> {
+    let Some(mut attempts) = REQUEST_ATTEMPTS.may_load(storage, (user.clone(), request_id))? else {
+        return Ok(())
+    };
+
+    if let Some(attempt) = attempts.last_mut().filter(|attempt| attempt.outcome == AttemptOutcome::Pending) {
+        attempt.outcome = outcome;
+        REQUEST_ATTEMPTS.save(storage, (user.clone(), request_id), &attempts)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Credit back to the relayer fee pool the fees of a settled packet that no relayer earned:
+ * the timeout fee once the packet is acknowledged, the receive and ack fees once it timed out,
+ * every fee once its channel is closed, the middleware refunds all the fees escrowed on a closing channel.
+ * Stale packets are matched too, their refund is paid regardless of the state of the request.
+ */
+pub(crate) fn release_fee_escrow(storage : &mut dyn Storage, user : &Addr, request_id : u128, is_packet : impl Fn(&IbcPacketOutgoing) -> bool, outcome : AttemptOutcome) -> StdResult<()> {
+    let escrow_key = (user.clone(), request_id);
+    let Some(mut escrows) = RELAYER_FEE_ESCROWS.may_load(storage, escrow_key.clone())? else {
+        return Ok(())
+    };
+    let Some(index) = escrows.iter().position(|escrow| is_packet(&escrow.packet)) else {
+        return Ok(())
+    };
+
+    let escrow = escrows.remove(index);
+    let refund = match outcome {
+        AttemptOutcome::Acknowledged => escrow.timeout_fee,
+        AttemptOutcome::TimedOut => escrow.receive_fee.checked_add(escrow.ack_fee)?,
+        _ => escrow.receive_fee.checked_add(escrow.ack_fee)?.checked_add(escrow.timeout_fee)?
+    };
+    if !refund.is_zero() {
+        RELAYER_FEE_POOL.update(storage, escrow.denom, |pool| -> StdResult<_> {
+            Ok(pool.unwrap_or_default().checked_add(refund)?)
+        })?;
+    }
+
+    if escrows.is_empty() {
+        RELAYER_FEE_ESCROWS.remove(storage, escrow_key);
+    } else {
+        RELAYER_FEE_ESCROWS.save(storage, escrow_key, &escrows)?;
+    }
+
+    Ok(())
+}
+
+//Every successful ack coming back on a channel is a liveness signal of its relayer and host
+pub(crate) fn record_channel_ack(storage : &mut dyn Storage, channel_id : &str, acked_at : u64, is_error : bool) -> StdResult<()> {
+    let mut health = CHANNELS_HEALTH.may_load(storage, channel_id.to_string())?.unwrap_or_default();
+    if is_error {
+        health.last_error_ack_at = Some(acked_at);
+    } else {
+        health.last_ack_at = Some(acked_at);
+    }
+    CHANNELS_HEALTH.save(storage, channel_id.to_string(), &health)
+}
+
+//The height reported by the host is the base of the height timeouts, reports delivered out of order can't lower it
+pub(crate) fn record_host_height(storage : &mut dyn Storage, host : String, height : u64, reported_at : u64) -> StdResult<()> {
+    if HOST_HEIGHTS.may_load(storage, host.clone())?.is_none_or(|known_height| known_height.height <= height) {
+        HOST_HEIGHTS.save(storage, host, &HostHeight { height, reported_at })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn record_channel_timeout(storage : &mut dyn Storage, channel_id : &str, timed_out_at : u64) -> StdResult<()> {
+    let mut health = CHANNELS_HEALTH.may_load(storage, channel_id.to_string())?.unwrap_or_default();
+    health.last_timeout_at = Some(timed_out_at);
+    CHANNELS_HEALTH.save(storage, channel_id.to_string(), &health)
+}
+
+//Readable form of a packet timeout, for the response attributes
+pub(crate) fn describe_timeout(timeout : &IbcTimeout) -> String {
+    let timestamp = timeout.timestamp().map(|timestamp| format!("{}s", timestamp.seconds()));
+    let block = timeout.block().map(|block| format!("height {}-{}", block.revision, block.height));
+
+    match (timestamp, block) {
+        (Some(timestamp), Some(block)) => format!("{} or {}", timestamp, block),
+        (Some(timeout), None) | (None, Some(timeout)) => timeout,
+        (None, None) => "none".to_string()
+    }
+}
+
+//Record the token as locked by the user, concretizing the user if it doesn't exist
+pub(crate) fn add_locked_token(storage : &mut dyn Storage, user : &Addr, collection : &str, token_id : &str, locked_at : u64, native_address : Option<String>) -> StdResult<()> {
+    let mut user_data = USERS_DATA.may_load(storage, user.clone())?
+        .unwrap_or(UserData {
+            address : user.clone(),
+            last_lock : 0,
+            lock_credits : 0
+        });
+    user_data.last_lock = locked_at;
+
+    LOCKED_TOKENS.save(storage, (collection.to_string(), token_id.to_string()), &LockedToken {
+        owner : user.clone(),
+        locked_at,
+        native_address
+    })?;
+    LAST_TOKEN_LOCKS.save(storage, (collection.to_string(), token_id.to_string()), &locked_at)?;
+    USERS_DATA.save(storage, user.clone(), &user_data)
+}
+
+pub(crate) fn remove_locked_token(storage : &mut dyn Storage, user : &Addr, collection : &str, token_id : &str) -> StdResult<()> {
+    if is_token_locked(storage, user, collection, token_id)? {
+        LOCKED_TOKENS.remove(storage, (collection.to_string(), token_id.to_string()))?;
+    }
+
+    Ok(())
+}
+
+//A request created before an emergency release of the token must not have any effect on it,
+//request ids are compared since a token can be released and locked again within the same block
+pub(crate) fn is_emergency_released(storage : &dyn Storage, request_id : u128, collection : &str, token_id : &str) -> StdResult<bool> {
+    Ok(
+        EMERGENCY_RELEASES.may_load(storage, (collection.to_string(), token_id.to_string()))?
+        .is_some_and(|release| release.last_request_id >= request_id)
+    )
 }
\ No newline at end of file
@@ -1,12 +1,12 @@
-use std::collections::HashMap;
-
-use cosmwasm_std::{ensure, from_json, Ibc3ChannelOpenResponse, IbcBasicResponse, IbcChannelCloseMsg, IbcChannelConnectMsg, IbcChannelOpenMsg, IbcChannelOpenResponse, IbcOrder, IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg, IbcReceiveResponse, StdError};
+use cosmwasm_std::{ensure, from_json, Addr, Event, Ibc3ChannelOpenResponse, IbcBasicResponse, IbcChannelCloseMsg, IbcChannelConnectMsg, IbcChannelOpenMsg, IbcChannelOpenResponse, IbcOrder, IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg, IbcReceiveResponse, StdError};
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::{entry_point, DepsMut, Env, StdResult};
 
-use crate::{datatypes::{AckMessage, ChannelInfo, IbcPacketOutgoing, PacketType, UserData}, helpers::send_nft, state::{CHANNEL, PENDING_PACKETS_REQUESTS, STATE, TIMED_OUT_UNLOCK_REQUESTS, USERS_DATA}};
-
-const IBC_APP_VERSION: &str = "gamefi-satellite-protocol-v1";
+use crate::datatypes::{AckMessage, AttemptOutcome, ChannelInfo, ChannelProtocol, HistoryAction, IncomingPacketType, PacketType, PendingPacket, PendingStatus, UserData};
+use crate::helpers::{add_locked_token, ensure_error, ensure_min_lock_duration, is_emergency_released, is_token_locked, load_token_requests, load_user_locked_tokens, record_attempt_outcome, record_channel_ack, record_channel_timeout, record_history, record_host_height, refund_credits, release_fee_escrow, remove_locked_token, remove_pending_request, save_pending_request, send_nft};
+use crate::ics721::{self, NonFungibleTokenPacketData};
+use crate::protocol::{self, negotiate_version, proposed_versions};
+use crate::state::{CHANNELS, HOST_CHANNELS, LOCKED_TOKENS, PENDING_PACKETS_REQUESTS, STATE, TIMED_OUT_UNLOCK_REQUESTS, USERS_DATA};
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn ibc_channel_open(
@@ -16,28 +16,37 @@ pub fn ibc_channel_open(
 ) -> StdResult<IbcChannelOpenResponse> {
     let channel = msg.channel();
 
-    ensure!(!CHANNEL.exists(deps.storage), StdError::generic_err("channel already exists"));
+    ensure!(!CHANNELS.has(deps.storage, channel.endpoint.channel_id.clone()), StdError::generic_err("channel already exists"));
  
     if channel.order != IbcOrder::Unordered {
         return Err(StdError::generic_err("only un-ordered channels are supported"));
     }
  
-    if let Some(counter_version) = msg.counterparty_version() {
-        if counter_version != IBC_APP_VERSION {
-            return Err(StdError::generic_err(format!(
-                "Counterparty version must be `{IBC_APP_VERSION}`"
-            )));
-        }
-    }
+    //On try the highest version shared with the counterparty is picked,
+    //on init the versions left by the relayer (or every satellite version) are proposed and the final one is stored on connect
+    let (protocol, version) = match msg.counterparty_version() {
+        Some(counter_version) => {
+            let protocol = negotiate_version(counter_version)?;
+            let version = protocol.version().to_string();
+            (protocol, version)
+        },
+        None if channel.version.is_empty() => (negotiate_version("")?, proposed_versions()),
+        None => (negotiate_version(&channel.version)?, channel.version.clone())
+    };
  
-    CHANNEL.save(deps.storage, &ChannelInfo {
+    //The channel is not bound to any host until the admin binds it
+    CHANNELS.save(deps.storage, channel.endpoint.channel_id.clone(), &ChannelInfo {
         channel_id: channel.endpoint.channel_id.clone(),
         finalized: false,
-        opened_at : env.block.time.seconds()
+        opened_at : env.block.time.seconds(),
+        host : None,
+        closed_at : None,
+        protocol,
+        fee_enabled : false
     })?;
  
     Ok(Some(Ibc3ChannelOpenResponse {
-        version: IBC_APP_VERSION.to_string(),
+        version,
     }))
 }
  
@@ -49,13 +58,18 @@ pub fn ibc_channel_connect(
 ) -> StdResult<IbcBasicResponse> {
     let channel = msg.channel();
     
-    let mut channel_info = CHANNEL.load(deps.storage)?;
+    let mut channel_info = CHANNELS.load(deps.storage, channel.endpoint.channel_id.clone())?;
     ensure!(!channel_info.finalized, StdError::generic_err("channel already finalized"));
-    debug_assert_eq!(channel_info.channel_id, channel.endpoint.channel_id, "channel ID mismatch");
  
+    //On ack the counterparty answers with the single version it picked from the proposal
+    if let Some(counter_version) = msg.counterparty_version() {
+        channel_info.protocol = ChannelProtocol::from_version(counter_version)
+            .ok_or_else(|| StdError::generic_err(format!("unsupported counterparty version `{counter_version}`")))?;
+    }
+
     // at this point, we are finished setting up the channel and can mark it as finalized
     channel_info.finalized = true;
-    CHANNEL.save(deps.storage, &channel_info)?;
+    CHANNELS.save(deps.storage, channel_info.channel_id.clone(), &channel_info)?;
  
     Ok(IbcBasicResponse::new())
 }
@@ -63,11 +77,29 @@ pub fn ibc_channel_connect(
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn ibc_channel_close(
     deps: DepsMut,
-    _env: Env,
-    _msg: IbcChannelCloseMsg,
+    env: Env,
+    msg: IbcChannelCloseMsg,
 ) -> StdResult<IbcBasicResponse> {
-    CHANNEL.remove(deps.storage);
-    Ok(IbcBasicResponse::new())
+    let channel_id = msg.channel().endpoint.channel_id.clone();
+    let mut channel_info = CHANNELS.load(deps.storage, channel_id.clone())?;
+
+    //The host stays without a channel until the admin binds a new one
+    if let Some(host) = channel_info.host.clone() {
+        if HOST_CHANNELS.may_load(deps.storage, host.clone())? == Some(channel_id.clone()) {
+            HOST_CHANNELS.remove(deps.storage, host);
+        }
+    }
+
+    //The channel is kept, its stranded requests are settled through ExecuteMsg::RecoverClosedChannel
+    channel_info.finalized = false;
+    channel_info.closed_at = Some(env.block.time.seconds());
+    CHANNELS.save(deps.storage, channel_id.clone(), &channel_info)?;
+
+    Ok(
+        IbcBasicResponse::new()
+        .add_attribute("response", "channel_closed")
+        .add_attribute("channel_id", channel_id)
+    )
 }
 
 //MAIN IBC LOGIC HERE
@@ -79,194 +111,456 @@ pub fn ibc_packet_ack(
     msg: IbcPacketAckMsg,
 ) -> StdResult<IbcBasicResponse> {
 
-    let ack_packet : AckMessage = from_json(&msg.acknowledgement.data)?;
-    let original_packet : IbcPacketOutgoing = from_json(&msg.original_packet.data)?;
-
-    match ack_packet {
-        AckMessage::Success { } => {
-            match original_packet.packet_type {
-                
-                //If the LockRequest was successful, concretize the user if it doesn't exist, or 'officially' consider the NFT locked
-                PacketType::LockRequest { user, token_id , collection, native_address : _} => {
-
-                    let mut user_data = match USERS_DATA.may_load(deps.storage, user.clone()) {
-                        Ok(Some(data)) => data,
-                        Ok(None) => UserData { 
-                            address : user.clone(),
-                            locked_tokens : HashMap::new(),
-                            last_lock : 0,
-                            lock_credits : 0
-                        },
-                        Err(err) => { 
-                            return Ok(
-                                IbcBasicResponse::new()
-                                .add_attribute("response", format!("failed to lock token {}, error {}", token_id, err.to_string()))
-                                .add_message(send_nft(collection, token_id, user.clone().to_string()))
-                            )
-                        }
-                    };
-
-                    //Concretize lock
-                    if let Some(locked_tokens) = user_data.locked_tokens.get_mut(&collection) {
-                        locked_tokens.push(token_id.clone());
-                    } else {
-                        user_data.locked_tokens.insert(collection.clone(), vec![token_id.clone()]);
-                    }
-
-                    user_data.last_lock = env.block.time.seconds();
-                    
-                    //Delete pending request
-                    PENDING_PACKETS_REQUESTS.remove(deps.storage, (user.clone(), original_packet.request_id));
-
-                    //Save user data
-                    USERS_DATA.save(deps.storage, user.clone(), &user_data)?;
-
-                    return Ok(
-                        IbcBasicResponse::new()
-                        .add_attribute("response" , format!("lock_token"))
-                        .add_attribute("user", user.clone().to_string())
-                        .add_attribute("token_id", token_id)
-                    )
+    let channel_id = msg.original_packet.src.channel_id.clone();
+    let channel_info = CHANNELS.load(deps.storage, channel_id.clone())?;
+    let protocol = channel_info.protocol;
+    let ack_packet = protocol::decode_ack(&protocol, &msg.acknowledgement.data);
+    let is_error = !matches!(ack_packet, Ok(AckMessage::Success {} | AckMessage::BatchResult { .. } | AckMessage::Pong { .. }));
+    record_channel_ack(deps.storage, &channel_id, env.block.time.seconds(), is_error)?;
+
+    if protocol::is_ping(&protocol, &msg.original_packet.data) {
+        //Pongs keep the host height fresh between the SyncState packets
+        if let (Ok(AckMessage::Pong { host_height }), Some(host)) = (&ack_packet, channel_info.host) {
+            record_host_height(deps.storage, host, *host_height, env.block.time.seconds())?;
+        }
 
-                },
-                PacketType::UnlockRequest { user, token_id , collection, native_address : _} => {
+        return Ok(
+            IbcBasicResponse::new()
+            .add_attribute("response", "pong")
+            .add_attribute("channel_id", channel_id)
+        )
+    }
 
-                    //Concretize unlock
-                    let mut user_data = USERS_DATA.load(deps.storage, user.clone())?;
+    let ack_packet = ack_packet?;
+    let (user, request_id) = protocol::decode_request_key(&protocol, &msg.original_packet.data)?;
+    release_fee_escrow(deps.storage, &user, request_id, |packet| protocol::matches_request(&protocol, &msg.original_packet.data, packet), AttemptOutcome::Acknowledged)?;
+
+    //The request may have been settled already by a channel recovery or an emergency unlock,
+    //or the ack may belong to a previous packet of a retried request, in both cases nothing is applied
+    let pending = PENDING_PACKETS_REQUESTS.may_load(deps.storage, (user.clone(), request_id))?;
+    let stale_reason = match &pending {
+        None => Some("request_already_settled"),
+        Some(pending) if pending.channel_id != channel_id => Some("channel_mismatch"),
+        //A pruned request is still settled by the ack of its last packet, until it is sent again
+        Some(pending) if pending.status == PendingStatus::TimedOut => Some("request_not_in_flight"),
+        Some(pending) if !protocol::matches_request(&protocol, &msg.original_packet.data, &pending.packet) => Some("packet_mismatch"),
+        Some(_) => None
+    };
+    let (Some(mut pending), None) = (pending, stale_reason) else {
+        return Ok(
+            IbcBasicResponse::new()
+            .add_attribute("response", "ignored_stale_ack")
+            .add_attribute("request_id", request_id.to_string())
+            .add_event(
+                Event::new("ignored_stale_ack")
+                .add_attribute("user", user.to_string())
+                .add_attribute("request_id", request_id.to_string())
+                .add_attribute("channel_id", channel_id)
+                .add_attribute("reason", stale_reason.unwrap_or_default())
+            )
+        )
+    };
+    let original_packet = pending.packet.clone();
+
+    //Outcome of every token of the request, no error means the host accepted it
+    let tokens = original_packet.packet_type.tokens();
+    let results : Vec<((String, String), Option<String>)> = match ack_packet {
+        AckMessage::Success { } => tokens.into_iter().map(|token| (token, None)).collect(),
+        AckMessage::Error { error } => tokens.into_iter().map(|token| (token, Some(error.clone()))).collect(),
+        AckMessage::BatchResult { results } => tokens
+            .into_iter()
+            .map(|(collection, token_id)| {
+                let error = results
+                    .iter()
+                    .find(|result| result.collection == collection && result.token_id == token_id)
+                    .map_or(Some("missing result for the token".to_string()), |result| result.error.clone());
+                ((collection, token_id), error)
+            })
+            .collect(),
+        //The host only answers satellite requests with Success/Error/BatchResult, a state snapshot or a pong is never expected here.
+        //It's a protocol error, the request is made recoverable: unlocks can be sent again, locks can be reclaimed with an emergency unlock
+        AckMessage::UserState { .. } | AckMessage::Pong { .. } => {
+            pending.status = PendingStatus::Recoverable;
+            save_pending_request(deps.storage, &user, request_id, &pending)?;
+            record_attempt_outcome(deps.storage, &user, request_id, AttemptOutcome::InvalidAck)?;
+
+            return Ok(
+                IbcBasicResponse::new()
+                .add_attribute("response", "unexpected_ack")
+                .add_attribute("packet_type", original_packet.packet_type.to_string())
+            )
+        }
+    };
+
+    remove_pending_request(deps.storage, &user, original_packet.request_id)?;
+    record_attempt_outcome(deps.storage, &user, original_packet.request_id, AttemptOutcome::Acknowledged)?;
+
+    //Tokens reclaimed through an emergency unlock are not touched by packets sent before the release
+    let mut active_results = vec![];
+    let mut response = IbcBasicResponse::new().add_attribute("user", user.to_string());
+
+    for ((collection, token_id), error) in results {
+        if is_emergency_released(deps.storage, original_packet.request_id, &collection, &token_id)? {
+            response = response
+                .add_attribute("response", "token_emergency_released")
+                .add_attribute("token_id", token_id);
+        } else {
+            active_results.push(((collection, token_id), error));
+        }
+    }
+
+    let credits_per_token = pending.credits.checked_div(active_results.len() as u16).unwrap_or_default();
+    let current_time = env.block.time.seconds();
+    let request_id = Some(original_packet.request_id);
+
+    for ((collection, token_id), error) in active_results {
+        response = match (original_packet.packet_type.is_lock(), error) {
+
+            //If the lock was successful, concretize the user if it doesn't exist, or 'officially' consider the NFT locked
+            (true, None) => {
+                add_locked_token(deps.storage, &user, &collection, &token_id, current_time, original_packet.packet_type.native_address().cloned())?;
+                record_history(deps.storage, current_time, &user, &collection, &token_id, request_id, HistoryAction::Lock)?;
+
+                response
+                .add_attribute("response" , "lock_token")
+                .add_attribute("token_id", token_id)
+            },
+
+            //Restore the ownership of the token, sending it back the the owner.
+            (true, Some(error)) => {
+                refund_credits(deps.storage, &user, credits_per_token)?;
+                record_history(deps.storage, current_time, &user, &collection, &token_id, request_id, HistoryAction::LockFailed { reason : error.clone() })?;
+
+                response
+                .add_attribute("response", "lock_token_fail")
+                .add_attribute("token_id", token_id.clone())
+                .add_attribute("reason", error)
+                .add_message(send_nft(collection, token_id, user.to_string()))
+            },
+
+            //Concretize unlock, send back the NFT and remove any pending timeout
+            (false, None) => {
+                if !is_token_locked(deps.storage, &user, &collection, &token_id)? {
+                    response
+                    .add_attribute("response", "token_already_unlocked")
+                    .add_attribute("token_id", token_id)
+                } else {
+                    remove_locked_token(deps.storage, &user, &collection, &token_id)?;
+                    TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, (collection.clone(), token_id.clone(), user.clone()));
+                    record_history(deps.storage, current_time, &user, &collection, &token_id, request_id, HistoryAction::Unlock)?;
+
+                    response
+                    .add_attribute("response" , "unlock_token")
+                    .add_attribute("token_id", token_id.clone())
+                    .add_message(send_nft(collection, token_id, user.to_string()))
+                }
+            },
 
-                    if let Some(tokens) = user_data.locked_tokens.get_mut(&collection) {
-                        tokens.retain(|x| *x != token_id);
-                    }
+            (false, Some(error)) => {
+                record_history(deps.storage, current_time, &user, &collection, &token_id, request_id, HistoryAction::UnlockFailed { reason : error.clone() })?;
 
-                    PENDING_PACKETS_REQUESTS.remove(deps.storage, (user.clone(), original_packet.request_id));
+                response
+                .add_attribute("response", "unlock_token_fail")
+                .add_attribute("token_id", token_id)
+                .add_attribute("reason", error)
+            }
+        };
+    }
 
-                    //Save user data and send back the NFT
-                    USERS_DATA.save(deps.storage, user.clone(), &user_data)?;
+    Ok(response)
+}
+ 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn ibc_packet_timeout(
+    deps: DepsMut,
+    env: Env,
+    msg: IbcPacketTimeoutMsg,
+) -> StdResult<IbcBasicResponse> {
 
-                    //Remove any pending timeout
-                    TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, (token_id.clone(), user.clone()));
+    let channel_id = msg.packet.src.channel_id.clone();
+    let channel_info = CHANNELS.load(deps.storage, channel_id.clone())?;
+    let protocol = channel_info.protocol;
+    record_channel_timeout(deps.storage, &channel_id, env.block.time.seconds())?;
+
+    if protocol::is_ping(&protocol, &msg.packet.data) {
+        return Ok(
+            IbcBasicResponse::new()
+            .add_attribute("response", "ping_timeout")
+            .add_attribute("channel_id", channel_id)
+        )
+    }
 
-                    return Ok(
-                        IbcBasicResponse::new()
-                        .add_attribute("response" , "unlock_token")
-                        .add_attribute("user", user.clone().to_string())
-                        .add_attribute("token_id", token_id.clone())
-                        .add_message(send_nft(collection, token_id, user.clone().to_string()))
-                    )
-                }
+    let (user, request_id) = protocol::decode_request_key(&protocol, &msg.packet.data)?;
+    //A timeout relayed after the channel closed, the fees were all refunded on close
+    let fee_outcome = if channel_info.closed_at.is_some() { AttemptOutcome::ChannelClosed } else { AttemptOutcome::TimedOut };
+    release_fee_escrow(deps.storage, &user, request_id, |packet| protocol::matches_request(&protocol, &msg.packet.data, packet), fee_outcome)?;
+
+    //Requests settled, or already timed out, are not touched. A pruned request is settled by the timeout of its last packet
+    let pending = PENDING_PACKETS_REQUESTS.may_load(deps.storage, (user.clone(), request_id))?;
+    let Some(pending) = pending.filter(|pending| {
+        pending.status != PendingStatus::TimedOut && pending.channel_id == channel_id && protocol::matches_request(&protocol, &msg.packet.data, &pending.packet)
+    }) else {
+        return Ok(
+            IbcBasicResponse::new()
+            .add_attribute("response", "request_already_settled")
+            .add_attribute("request_id", request_id.to_string())
+        )
+    };
+    let original_data = pending.packet.clone();
+
+    remove_pending_request(deps.storage, &user, original_data.request_id)?;
+    record_attempt_outcome(deps.storage, &user, original_data.request_id, AttemptOutcome::TimedOut)?;
+
+    let current_time = env.block.time.seconds();
+    let mut response = IbcBasicResponse::new()
+        .add_attribute("reason", "IBC package timeout")
+        .add_attribute("user", user.to_string());
+
+    if original_data.packet_type.is_lock() {
+        //Return credits to the user, and the tokens that weren't reclaimed in the meantime
+        refund_credits(deps.storage, &user, pending.credits)?;
+
+        for (collection, token_id) in original_data.packet_type.tokens() {
+            if !is_emergency_released(deps.storage, original_data.request_id, &collection, &token_id)? {
+                record_history(deps.storage, current_time, &user, &collection, &token_id, Some(request_id), HistoryAction::LockTimedOut)?;
+                response = response.add_message(send_nft(collection, token_id, user.to_string()));
             }
-        },
-        //Handle the controller error flow from the main contract
-        AckMessage::Error { error } => {
-            match original_packet.packet_type {
+        }
 
-                //Restore the ownership of the token, sending it back the the owner.
-                PacketType::LockRequest { user, token_id, collection , native_address : _} => {
+        return Ok(response)
+    }
 
-                    //Return credit to the user
-                    let mut user_data = USERS_DATA.load(deps.storage, user.clone())?;
-                    user_data.lock_credits += 1;
-                    USERS_DATA.save(deps.storage, user.clone(), &user_data)?;
+    let state = STATE.load(deps.storage)?;
+    let mut retryable = false;
 
-                    PENDING_PACKETS_REQUESTS.remove(deps.storage, (user.clone(), original_packet.request_id));
+    for (collection, token_id) in original_data.packet_type.tokens() {
+        if is_emergency_released(deps.storage, original_data.request_id, &collection, &token_id)? {
+            continue;
+        }
 
-                    return Ok(
-                        IbcBasicResponse::new()
-                        .add_attribute("response", "lock_token_fail")
-                        .add_attribute("user", user.clone().to_string())
-                        .add_attribute("token_id", token_id.clone())
-                        .add_attribute("reason", error)
-                        .add_message(send_nft(collection, token_id, user.clone().to_string()))
-                    )
+        let unlock_request_key = (collection.clone(), token_id.clone(), user.clone());
+        let mut consecutive_timeouts = TIMED_OUT_UNLOCK_REQUESTS.load(deps.storage, unlock_request_key.clone()).unwrap_or_default();
 
-                },
-                PacketType::UnlockRequest { user, token_id, collection: _ , native_address : _} => {
+        //After 3 consecutive timeout, the NFT can be unlocked, a relayer issue is a team problem, shouldn't mine the ownership of NFTs
+        if consecutive_timeouts == state.ibc_settings.max_timeouts {
 
-                    PENDING_PACKETS_REQUESTS.remove(deps.storage, (user.clone(), original_packet.request_id));
+            TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, unlock_request_key.clone());
+            remove_locked_token(deps.storage, &user, &collection, &token_id)?;
+            record_history(deps.storage, current_time, &user, &collection, &token_id, Some(request_id), HistoryAction::ForceUnlock { reason : "max_timeout_reached".to_string() })?;
 
-                    return Ok(
-                        IbcBasicResponse::new()
-                        .add_attribute("response", "unlock_token_fail")
-                        .add_attribute("user", user.clone().to_string())
-                        .add_attribute("token_id", token_id)
-                        .add_attribute("reason", error)
-                    )
-                }
-            }
+            response = response
+                .add_attribute("response" , "unlock_token_force")
+                .add_attribute("reason", "max_timeout_reached")
+                .add_attribute("token_id", token_id.clone())
+                .add_message(send_nft(collection, token_id, user.to_string()));
+
+            continue;
         }
+
+        consecutive_timeouts += 1;
+        TIMED_OUT_UNLOCK_REQUESTS.save(deps.storage, unlock_request_key.clone(), &consecutive_timeouts)?;
+        record_history(deps.storage, current_time, &user, &collection, &token_id, Some(request_id), HistoryAction::UnlockTimedOut)?;
+
+        retryable = true;
+        response = response.add_attribute("response", format!("failed to unlock token {}", token_id));
+    }
+
+    //The request is kept for the tokens still locked, it can be sent again with ExecuteMsg::RetryUnlock
+    if retryable {
+        save_pending_request(deps.storage, &user, original_data.request_id, &PendingPacket {
+            status : PendingStatus::TimedOut,
+            ..pending
+        })?;
+        response = response.add_attribute("retryable request id", original_data.request_id.to_string());
     }
+
+    Ok(response)
 }
- 
+
+/**
+ * Handle the commands sent by the host (main) contract.
+ * Every command is answered with an AckMessage, errors never fail the transaction,
+ * they are returned to the host as AckMessage::Error.
+ */
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn ibc_packet_timeout(
+pub fn ibc_packet_receive(
     deps: DepsMut,
-    _env: Env,
-    msg: IbcPacketTimeoutMsg,
-) -> StdResult<IbcBasicResponse> {
-
-    let original_data : IbcPacketOutgoing = from_json(&msg.packet.data)?;
-
-    match original_data.packet_type {
-        PacketType::LockRequest { user, token_id , collection, native_address : _} => {
+    env: Env,
+    msg: IbcPacketReceiveMsg,
+) -> StdResult<IbcReceiveResponse> {
 
-            //Return credit to the user
-            let mut user_data = USERS_DATA.load(deps.storage, user.clone())?;
-            user_data.lock_credits += 1;
-            USERS_DATA.save(deps.storage, user.clone(), &user_data)?;
-            
-            PENDING_PACKETS_REQUESTS.remove(deps.storage, (user.clone(), original_data.request_id));
-            
-            Ok(
-                IbcBasicResponse::new()
-                .add_attribute("reason", "IBC package timeout")
-                .add_message(send_nft(collection, token_id, user.clone().to_string()))
+    let channel_info = CHANNELS.may_load(deps.storage, msg.packet.dest.channel_id.clone())?;
+    let protocol = channel_info.as_ref().map_or(ChannelProtocol::SatelliteV1, |channel_info| channel_info.protocol.clone());
+
+    //ICS-721 hosts return the locked tokens with a standard transfer, they don't send satellite commands
+    if let Some(channel_info) = channel_info.clone().filter(|_| protocol == ChannelProtocol::Ics721) {
+        return match receive_ics721_transfer(deps, env, &msg, channel_info) {
+            Ok(response) => Ok(response),
+            Err(err) => Ok(
+                IbcReceiveResponse::new(ics721::ack_fail(err.to_string())?)
+                .add_attribute("response", "ics721_transfer_fail")
+                .add_attribute("reason", err.to_string())
             )
-        },
-        PacketType::UnlockRequest { user, token_id, collection , native_address : _} => {
-            let unlock_request_key = (token_id.clone(), user.clone());
-            
-            let state = STATE.load(deps.storage)?;
-
-            let mut consecutive_timeouts = match TIMED_OUT_UNLOCK_REQUESTS.load(deps.storage, unlock_request_key.clone()) {
-                Ok(e) => e,
-                Err(_) => 0,
-            };
-
-            //After 3 consecutive timeout, the NFT can be unlocked, a relayer issue is a team problem, shouldn't mine the ownership of NFTs
-            if consecutive_timeouts == state.ibc_settings.max_timeouts {
-
-                TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, unlock_request_key.clone());
-
-                return Ok(
-                    IbcBasicResponse::new()
-                    .add_attribute("response" , "unlock_token_force")
-                    .add_attribute("reason", "max_timeout_reached")
-                    .add_attribute("user", user.clone().to_string())
-                    .add_attribute("token_id", token_id.clone())
-                    .add_message(send_nft(collection, token_id, user.clone().to_string()))
-                )
+        }
+    }
 
+    let result = protocol::decode_command(&protocol, &msg.packet.data)
+        .and_then(|packet| {
+            //Only channels bound to a host can send commands
+            let host = channel_info
+                .and_then(|channel_info| channel_info.host)
+                .ok_or_else(|| StdError::generic_err("packet received from an unknown channel"))?;
+
+            match packet.packet_type {
+                IncomingPacketType::ForceUnlock { user, token_id, collection, reason } => {
+                    let state = STATE.load(deps.storage)?;
+                    ensure!(
+                        state.collections_info.iter().any(|collection_info| collection_info.address == collection && collection_info.host == host),
+                        ensure_error("The collection is not handled by this host".to_string())
+                    );
+                    receive_force_unlock(deps, env, &protocol, user, collection, token_id, reason)
+                },
+                IncomingPacketType::GrantCredits { user, amount } => receive_grant_credits(deps, env, &protocol, user, amount),
+                IncomingPacketType::SyncState { user, host_height } => receive_sync_state(deps, env, &protocol, host, user, host_height),
             }
+        });
+
+    match result {
+        Ok(response) => Ok(response),
+        Err(err) => Ok(
+            IbcReceiveResponse::new(protocol::encode_ack(&protocol, &AckMessage::Error { error: err.to_string() })?)
+            .add_attribute("response", "host_command_fail")
+            .add_attribute("reason", err.to_string())
+        )
+    }
+}
 
-            consecutive_timeouts += 1;
+/**
+ * A token locked through an ICS-721 channel comes back as a transfer of the host voucher.
+ * The class id of a returning voucher is prefixed by the host port and channel, what follows is the locked collection.
+ * The tokens are released to the transfer receiver, the host already burned the vouchers of the owner.
+ */
+fn receive_ics721_transfer(deps: DepsMut, env: Env, msg: &IbcPacketReceiveMsg, channel_info: ChannelInfo) -> StdResult<IbcReceiveResponse> {
+    let packet_data : NonFungibleTokenPacketData = from_json(&msg.packet.data)?;
+    let host = channel_info.host.ok_or_else(|| StdError::generic_err("packet received from an unknown channel"))?;
+
+    let voucher_prefix = format!("{}/{}/", msg.packet.src.port_id, msg.packet.src.channel_id);
+    let collection = packet_data.class_id.strip_prefix(&voucher_prefix)
+        .ok_or_else(|| ensure_error("Only tokens locked in the satellite can be received".to_string()))?
+        .to_string();
+
+    let state = STATE.load(deps.storage)?;
+    ensure!(
+        state.collections_info.iter().any(|collection_info| collection_info.address == collection && collection_info.host == host),
+        ensure_error("The collection is not handled by this host".to_string())
+    );
+
+    let receiver = deps.api.addr_validate(&packet_data.receiver)?;
+    let mut response = IbcReceiveResponse::new(ics721::ack_success()?)
+        .add_attribute("response", "unlock_token")
+        .add_attribute("receiver", receiver.to_string());
+
+    //Every token is checked before touching the ledger, a failed transfer is acked without side effects.
+    //A return is an unlock started from the host, it follows the same minimum lock duration
+    let owners = packet_data.token_ids
+        .into_iter()
+        .map(|token_id| {
+            let locked_token = LOCKED_TOKENS.may_load(deps.storage, (collection.clone(), token_id.clone()))?
+                .ok_or_else(|| ensure_error(format!("The token {} is not locked in the contract", token_id)))?;
+            ensure_min_lock_duration(&state, &collection, &token_id, &locked_token, env.block.time.seconds())?;
+            Ok((token_id, locked_token.owner))
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    for (token_id, owner) in owners {
+        remove_locked_token(deps.storage, &owner, &collection, &token_id)?;
+        TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, (collection.clone(), token_id.clone(), owner.clone()));
+        record_history(deps.storage, env.block.time.seconds(), &owner, &collection, &token_id, None, HistoryAction::Unlock)?;
+
+        response = response
+            .add_attribute("token_id", token_id.clone())
+            .add_message(send_nft(collection.clone(), token_id, receiver.to_string()));
+    }
 
-            TIMED_OUT_UNLOCK_REQUESTS.save(deps.storage, unlock_request_key.clone(), &consecutive_timeouts)?;
-            PENDING_PACKETS_REQUESTS.remove(deps.storage, (user.clone(), original_data.request_id));
-            
-            Ok(
-                IbcBasicResponse::new()
-                .add_attribute("response", format!("failed to unlock token {}", token_id))
-                .add_attribute("reason", "IBC package timeout")
-            )
-        }
+    Ok(response)
+}
+
+/**
+ * The host considers the token released, remove it from the user locked tokens and send it back.
+ * Any pending unlock request for the same token is dropped, so a late ack can't send the NFT twice.
+ */
+fn receive_force_unlock(deps: DepsMut, env: Env, protocol: &ChannelProtocol, user: Addr, collection: String, token_id: String, reason: Option<String>) -> StdResult<IbcReceiveResponse> {
+    ensure!(is_token_locked(deps.storage, &user, &collection, &token_id)?, ensure_error("The token is not locked in the contract, or not owned by the user".to_string()));
+
+    remove_locked_token(deps.storage, &user, &collection, &token_id)?;
+
+    //Batch unlocks are left to the ack guard, the token is no longer locked when they settle
+    let pending_unlocks = load_token_requests(deps.storage, &user, &collection, &token_id)?
+        .into_iter()
+        .filter(|(_, pending)| matches!(pending.packet.packet_type, PacketType::UnlockRequest { .. }))
+        .map(|(request_id, _)| request_id);
+
+    for request_id in pending_unlocks {
+        remove_pending_request(deps.storage, &user, request_id)?;
     }
+
+    TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, (collection.clone(), token_id.clone(), user.clone()));
+
+    let reason = reason.unwrap_or("host_request".to_string());
+    record_history(deps.storage, env.block.time.seconds(), &user, &collection, &token_id, None, HistoryAction::ForceUnlock { reason : reason.clone() })?;
+
+    Ok(
+        IbcReceiveResponse::new(protocol::encode_ack(protocol, &AckMessage::Success { })?)
+        .add_attribute("response", "unlock_token_force")
+        .add_attribute("reason", reason)
+        .add_attribute("user", user.to_string())
+        .add_attribute("token_id", token_id.clone())
+        .add_message(send_nft(collection, token_id, user.to_string()))
+    )
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn ibc_packet_receive(
-    _deps: DepsMut,
-    _env: Env,
-    _msg: IbcPacketReceiveMsg,
-) -> StdResult<IbcReceiveResponse> {
-    Ok(IbcReceiveResponse::new(b""))
-}
\ No newline at end of file
+fn receive_grant_credits(deps: DepsMut, _env: Env, protocol: &ChannelProtocol, user: Addr, amount: u16) -> StdResult<IbcReceiveResponse> {
+    deps.api.addr_validate(user.as_str())?;
+
+    let mut user_data = USERS_DATA.may_load(deps.storage, user.clone())?
+        .unwrap_or(UserData {
+            address : user.clone(),
+            last_lock : 0,
+            lock_credits : 0
+        });
+
+    user_data.lock_credits = user_data.lock_credits.checked_add(amount)
+        .ok_or_else(|| ensure_error("Lock credits overflow".to_string()))?;
+
+    USERS_DATA.save(deps.storage, user.clone(), &user_data)?;
+
+    Ok(
+        IbcReceiveResponse::new(protocol::encode_ack(protocol, &AckMessage::Success { })?)
+        .add_attribute("response", "grant_credits")
+        .add_attribute("user", user.to_string())
+        .add_attribute("new credits balance", user_data.lock_credits.to_string())
+    )
+}
+
+//Answer with a snapshot of the user data, so the host can reconcile its own ledger
+fn receive_sync_state(deps: DepsMut, env: Env, protocol: &ChannelProtocol, host: String, user: Addr, host_height: Option<u64>) -> StdResult<IbcReceiveResponse> {
+    if let Some(host_height) = host_height {
+        record_host_height(deps.storage, host, host_height, env.block.time.seconds())?;
+    }
+
+    let lock_credits = USERS_DATA.may_load(deps.storage, user.clone())?
+        .map(|data| data.lock_credits)
+        .unwrap_or(0);
+
+    let ack = AckMessage::UserState {
+        user : user.clone(),
+        locked_tokens : load_user_locked_tokens(deps.storage, &user)?,
+        lock_credits
+    };
+
+    Ok(
+        IbcReceiveResponse::new(protocol::encode_ack(protocol, &ack)?)
+        .add_attribute("response", "sync_state")
+        .add_attribute("user", user.to_string())
+    )
+}
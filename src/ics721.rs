@@ -0,0 +1,107 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{ensure, from_json, to_json_binary, to_json_string, Addr, Binary, StdError, StdResult};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{datatypes::{AckMessage, HostInfo, IbcPacketOutgoing, PacketType}, helpers::{ensure_error, host_address}};
+
+//Channels opened with this version speak the standard ICS-721 packet format instead of the satellite protocol
+pub const ICS721_VERSION: &str = "ics721-1";
+
+//ICS-721 packet payload, as specified by the standard (camelCase, optional fields omitted)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NonFungibleTokenPacketData {
+    pub class_id : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_uri : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_data : Option<Binary>,
+    pub token_ids : Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_uris : Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_data : Option<Vec<Binary>>,
+    pub sender : String,
+    pub receiver : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo : Option<String>
+}
+
+#[cw_serde]
+pub enum Ics721Ack {
+    Result(Binary),
+    Error(String)
+}
+
+//Carried in the packet memo, identifies the pending request when the ack or the timeout comes back
+#[cw_serde]
+pub struct SatelliteMemo {
+    pub satellite_request_id : u128
+}
+
+/**
+ * Encode a lock request as an ICS-721 transfer of the locked tokens.
+ * The tokens are received on the host by the user address derived from the host chain prefix, or by the native address if provided.
+ * Unlocks are not requested by the satellite, the host returns the tokens with a standard ICS-721 transfer.
+ */
+pub(crate) fn encode_request(request : &IbcPacketOutgoing, host : &HostInfo) -> StdResult<Binary> {
+    let (user, native_address) = match &request.packet_type {
+        PacketType::LockRequest { user, native_address, .. } | PacketType::BatchLockRequest { user, native_address, .. } => (user, native_address),
+        _ => return Err(ensure_error("Unlocks through an ICS-721 channel must be initiated from the host".to_string()))
+    };
+
+    let tokens = request.packet_type.tokens();
+    let class_id = tokens.first().map(|(collection, _)| collection.clone()).unwrap_or_default();
+    ensure!(tokens.iter().all(|(collection, _)| *collection == class_id), ensure_error("An ICS-721 transfer can only contain tokens of a single collection".to_string()));
+
+    let receiver = host_address(host, user, native_address.as_ref())?;
+
+    to_json_binary(&NonFungibleTokenPacketData {
+        class_id,
+        class_uri : None,
+        class_data : None,
+        token_ids : tokens.into_iter().map(|(_, token_id)| token_id).collect(),
+        token_uris : None,
+        token_data : None,
+        sender : user.to_string(),
+        receiver,
+        memo : Some(to_json_string(&SatelliteMemo { satellite_request_id : request.request_id })?)
+    })
+}
+
+//Key of the pending request a sent transfer belongs to
+pub(crate) fn decode_request_key(data : &Binary) -> StdResult<(Addr, u128)> {
+    let packet_data : NonFungibleTokenPacketData = from_json(data)?;
+    let memo = packet_data.memo.ok_or_else(|| StdError::generic_err("transfer not sent by the satellite"))?;
+    let memo : SatelliteMemo = from_json(memo.as_bytes())?;
+
+    Ok((Addr::unchecked(packet_data.sender), memo.satellite_request_id))
+}
+
+//The transfer carries the request id and the tokens of the request
+pub(crate) fn matches_request(data : &Binary, request : &IbcPacketOutgoing) -> bool {
+    let Ok(packet_data) = from_json::<NonFungibleTokenPacketData>(data) else {
+        return false
+    };
+    let memo = packet_data.memo.and_then(|memo| from_json::<SatelliteMemo>(memo.as_bytes()).ok());
+
+    memo.is_some_and(|memo| memo.satellite_request_id == request.request_id) &&
+    packet_data.token_ids == request.packet_type.tokens().into_iter().map(|(_, token_id)| token_id).collect::<Vec<_>>() &&
+    request.packet_type.tokens().iter().all(|(collection, _)| *collection == packet_data.class_id)
+}
+
+pub(crate) fn decode_ack(data : &Binary) -> StdResult<AckMessage> {
+    Ok(match from_json(data)? {
+        Ics721Ack::Result(_) => AckMessage::Success { },
+        Ics721Ack::Error(error) => AckMessage::Error { error }
+    })
+}
+
+pub(crate) fn ack_success() -> StdResult<Binary> {
+    to_json_binary(&Ics721Ack::Result(Binary::from(vec![1])))
+}
+
+pub(crate) fn ack_fail(error : String) -> StdResult<Binary> {
+    to_json_binary(&Ics721Ack::Error(error))
+}
@@ -1,10 +1,18 @@
 #[cfg(test)]
 mod test
 {
-    use cosmwasm_std::{to_json_binary, Addr, Coin, Empty, Timestamp};
+    use std::collections::HashMap;
+
+    use cosmwasm_std::{coins, from_json, testing::{message_info, mock_dependencies, mock_env, mock_ibc_channel_close_init, mock_ibc_channel_connect_ack, mock_ibc_channel_connect_confirm, mock_ibc_channel_open_init, mock_ibc_channel_open_try, mock_ibc_packet_ack, mock_ibc_packet_recv, mock_ibc_packet_timeout, MockApi, MockQuerier, MockStorage}, to_json_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Empty, ContractResult, Env, IbcAcknowledgement, IbcOrder, IbcTimeoutBlock, Order, OwnedDeps, Response, Storage, SystemResult, Timestamp, Uint128, WasmMsg};
+    use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
+    use cw721::OwnerOfResponse;
     use cw_multi_test::{App, Contract, ContractWrapper, Executor};
+    use cw_storage_plus::Map;
+    use serde::Serialize;
+
+    use crate::{contract::{execute, instantiate, migrate, query}, datatypes::{AckMessage, AssetRevenue, Campaign, AttemptOutcome, BatchItemResult, ChannelHealth, ChannelInfo, ChannelProtocol, CollectionInfo, CreditPrice, CreditQuote, Cw20HookMsg, Cw721ReceiveMsg, HistoryAction, HistoryEntry, HostInfo, IbcPacketIncoming, HeightTimeout, IbcPacketOutgoing, IbcSettings, IncomingPacketType, LockedToken, NftReceiveMsg, PacketType, PaymentAsset, PendingPacket, PendingStatus, PriceTier, Promotion, RelayerFee, RelayerFeeSettings, RequestAttempt, State, TimeoutKind, TokenLockResponse, TokenStatus, TreasurySettings, TreasurySplit, UserDataResponse}, helpers::add_locked_token, ibc::{ibc_channel_close, ibc_channel_connect, ibc_channel_open, ibc_packet_ack, ibc_packet_receive, ibc_packet_timeout}, ics721::{self, Ics721Ack, NonFungibleTokenPacketData, ICS721_VERSION}, protocol::{VersionedPayload, IBC_APP_VERSION_V1, IBC_APP_VERSION_V2}, msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, UpdateStatePayload}, state::{CHANNELS, HOST_CHANNELS, LOCKED_TOKENS, PENDING_PACKETS_REQUESTS, USERS_DATA}};
 
-    use crate::{contract::{execute, instantiate, migrate, query}, datatypes::{ChannelInfo, CollectionInfo, Cw721ReceiveMsg, IbcSettings, NftReceiveMsg, PacketType}, msg::{ExecuteMsg, InstantiateMsg, QueryMsg}};
+    const COLLECTION: &str = "osmo1xqw2sl9zk8a6pch0csaw78n4swg5ws8t62wc5qta4gnjxfqg6v2qcs777k";
 
     #[test]
     fn test_instantiate_contract() {
@@ -37,7 +45,7 @@ mod test
         let mut app = mock_app();
         let admin = Addr::unchecked("osmo1cw2ap3sxk6yn7j4sgj0zj4qlr30f4pm23enp3v");
         let user = Addr::unchecked("osmo1pw2ap3sxk6yn7j4sgj0zj4qlr30f4pm23enp4v");
-        let cw721_contract = Addr::unchecked("osmo1xqw2sl9zk8a6pch0csaw78n4swg5ws8t62wc5qta4gnjxfqg6v2qcs777k");
+        let cw721_contract = Addr::unchecked(COLLECTION);
 
         let code_id = app.store_code(contract_satellite());
         let msg = default_instantiate_msg();
@@ -46,11 +54,11 @@ mod test
 
         app.update_block(|block| block.time = Timestamp::from_seconds(1_000_000));
 
-        app.contract_storage_mut(&contract_addr).set("channel".as_bytes(), to_json_binary(&ChannelInfo {
-            channel_id: "channel-0".to_string(),
-            finalized: true,
-            opened_at: 1_000_000,
-        }).unwrap().as_slice());
+        // Locks cost credits, buy one before sending the NFT
+        app.init_modules(|router, _, storage| router.bank.init_balance(storage, &user, coins(100_000, "uosmo"))).unwrap();
+        app.execute_contract(user.clone(), contract_addr.clone(), &ExecuteMsg::GetCredits { amount: 1 }, &coins(100_000, "uosmo")).unwrap();
+
+        open_channel(app.contract_storage_mut(&contract_addr).as_mut(), "channel-0", Some("osmosis"));
 
         // Send NFT
         let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
@@ -63,7 +71,7 @@ mod test
                 ).unwrap(),
         });
 
-        let _ = app.execute_contract(cw721_contract, contract_addr.clone(), &nft_msg, &[]);
+        app.execute_contract(cw721_contract, contract_addr.clone(), &nft_msg, &[]).unwrap();
         
         let packets: Vec<PacketType> = app.wrap()
             .query_wasm_smart(
@@ -89,7 +97,7 @@ mod test
         
         // This user hasn't locked anything yet
         let unlock_msg = ExecuteMsg::UnlockToken {
-            collection: "osmo1xqw2sl9zk8a6pch0csaw78n4swg5ws8t62wc5qta4gnjxfqg6v2qcs777k".to_string(),
+            collection: COLLECTION.to_string(),
             token_id: "1".to_string(),
             native_address : None
         };
@@ -98,6 +106,1339 @@ mod test
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_receive_host_commands() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+
+        // Credits granted by the host are added to the user balance
+        let grant = IbcPacketIncoming {
+            request_id: 1,
+            timestamp: 1_000_000,
+            packet_type: IncomingPacketType::GrantCredits { user: user.clone(), amount: 5 },
+        };
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &grant).unwrap()).unwrap();
+        let ack: AckMessage = from_json(response.acknowledgement.unwrap()).unwrap();
+        assert_eq!(ack, AckMessage::Success {});
+
+        let sync = IbcPacketIncoming {
+            request_id: 2,
+            timestamp: 1_000_000,
+            packet_type: IncomingPacketType::SyncState { user: user.clone(), host_height: None },
+        };
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &sync).unwrap()).unwrap();
+        let ack: AckMessage = from_json(response.acknowledgement.unwrap()).unwrap();
+        assert_eq!(ack, AckMessage::UserState { user: user.clone(), locked_tokens: HashMap::new(), lock_credits: 5 });
+
+        // Force unlocking a token that is not locked is answered with an error ack
+        let force_unlock = IbcPacketIncoming {
+            request_id: 3,
+            timestamp: 1_000_000,
+            packet_type: IncomingPacketType::ForceUnlock { user, token_id: "1".to_string(), collection: "collection".to_string(), reason: None },
+        };
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &force_unlock).unwrap()).unwrap();
+        let ack: AckMessage = from_json(response.acknowledgement.unwrap()).unwrap();
+        assert!(matches!(ack, AckMessage::Error { .. }));
+    }
+
+    #[test]
+    fn test_bind_channel_routes_host_collections() {
+        let mut app = mock_app();
+        let admin = Addr::unchecked("osmo1cw2ap3sxk6yn7j4sgj0zj4qlr30f4pm23enp3v");
+        let cw721_contract = Addr::unchecked(COLLECTION);
+
+        let code_id = app.store_code(contract_satellite());
+        let contract_addr = app.instantiate_contract(code_id, admin.clone(), &default_instantiate_msg(), &[], "test_instantiate", Some(admin.to_string())).unwrap();
+
+        open_channel(app.contract_storage_mut(&contract_addr).as_mut(), "channel-0", None);
+        open_channel(app.contract_storage_mut(&contract_addr).as_mut(), "channel-1", None);
+
+        // Only the admin can bind, and only to a known host
+        let bind_msg = ExecuteMsg::BindChannel { channel_id: "channel-1".to_string(), host: "osmosis".to_string(), fee_enabled: None };
+        assert!(app.execute_contract(cw721_contract, contract_addr.clone(), &bind_msg, &[]).is_err());
+        assert!(app.execute_contract(admin.clone(), contract_addr.clone(), &ExecuteMsg::BindChannel { channel_id: "channel-1".to_string(), host: "juno".to_string(), fee_enabled: None }, &[]).is_err());
+        app.execute_contract(admin.clone(), contract_addr.clone(), &bind_msg, &[]).unwrap();
+
+        let channels: Vec<ChannelInfo> = app.wrap()
+            .query_wasm_smart(contract_addr.clone(), &QueryMsg::GetChannels { start_after: None, limit: None })
+            .unwrap();
+
+        assert_eq!(channels[0].host, None);
+        assert_eq!(channels[1].host, Some("osmosis".to_string()));
+    }
+
+    #[test]
+    fn test_recover_closed_channel_refunds_pending_locks() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION;
+
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(200_000, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        for token_id in ["1", "2"] {
+            let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+                sender: user.to_string(),
+                token_id: token_id.to_string(),
+                msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+            });
+            execute(deps.as_mut(), mock_env(), message_info(&Addr::unchecked(collection), &[]), nft_msg).unwrap();
+        }
+
+        // Recovery is only possible once the channel is closed
+        let recover_msg = ExecuteMsg::RecoverClosedChannel { channel_id: "channel-0".to_string(), limit: Some(1) };
+        assert!(execute(deps.as_mut(), mock_env(), message_info(&user, &[]), recover_msg.clone()).is_err());
+
+        ibc_channel_close(deps.as_mut(), mock_env(), mock_ibc_channel_close_init("channel-0", IbcOrder::Unordered, "gamefi-satellite-protocol-v1")).unwrap();
+
+        // Every batch settles the next requests of the channel, the NFTs are sent back and the credits refunded
+        for refunded_credits in [1, 2] {
+            let response = execute(deps.as_mut(), mock_env(), message_info(&user, &[]), recover_msg.clone()).unwrap();
+            assert_eq!(response.messages.len(), 1);
+            assert_eq!(USERS_DATA.load(deps.as_ref().storage, user.clone()).unwrap().lock_credits, refunded_credits);
+        }
+        let response = execute(deps.as_mut(), mock_env(), message_info(&user, &[]), recover_msg).unwrap();
+        assert!(response.messages.is_empty());
+        let history: Vec<HistoryEntry> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetTokenHistory { collection: collection.to_string(), token_id: "2".to_string(), start_after: None, limit: None }).unwrap()).unwrap();
+        assert_eq!(history.last().unwrap().action, HistoryAction::LockFailed { reason: "channel_closed".to_string() });
+        assert!(PENDING_PACKETS_REQUESTS.is_empty(deps.as_ref().storage));
+        assert!(!HOST_CHANNELS.has(deps.as_ref().storage, "osmosis".to_string()));
+    }
+
+    #[test]
+    fn test_recover_closed_channel_skips_emergency_released_tokens() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.emergency_unlock_grace = Some(600);
+        let (mut deps, _, user) = setup(msg);
+        let collection = COLLECTION.to_string();
+
+        let owner = user.to_string();
+        deps.querier.update_wasm(move |_| SystemResult::Ok(ContractResult::Ok(to_json_binary(&OwnerOfResponse { owner: owner.clone(), approvals: vec![] }).unwrap())));
+
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(200_000, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        let tokens = vec![(collection.clone(), "1".to_string()), (collection.clone(), "2".to_string())];
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), ExecuteMsg::LockTokens { tokens, native_address: None }).unwrap();
+
+        // The first token of the stuck batch is reclaimed
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(600);
+        let emergency_msg = ExecuteMsg::EmergencyUnlock { collection: collection.clone(), token_id: "1".to_string() };
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), emergency_msg).unwrap();
+
+        // The recovery only sends back the other token, and refunds its credit
+        ibc_channel_close(deps.as_mut(), env.clone(), mock_ibc_channel_close_init("channel-0", IbcOrder::Unordered, "gamefi-satellite-protocol-v1")).unwrap();
+        let recover_msg = ExecuteMsg::RecoverClosedChannel { channel_id: "channel-0".to_string(), limit: None };
+        let response = execute(deps.as_mut(), env, message_info(&user, &[]), recover_msg).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0].msg, CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: collection.clone(),
+            msg: to_json_binary(&cw721::Cw721ExecuteMsg::TransferNft { recipient: user.to_string(), token_id: "2".to_string() }).unwrap(),
+            funds: vec![]
+        }));
+        assert_eq!(USERS_DATA.load(deps.as_ref().storage, user.clone()).unwrap().lock_credits, 2);
+        assert!(PENDING_PACKETS_REQUESTS.is_empty(deps.as_ref().storage));
+    }
+
+    #[test]
+    fn test_emergency_unlock_when_request_is_stuck() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.emergency_unlock_grace = Some(600);
+        let (mut deps, _, user) = setup(msg);
+        let collection = COLLECTION;
+
+        mock_lock(&mut deps, &user, "1");
+
+        // The unlock packet is never relayed
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let unlock_packet = last_pending_packet(&deps, &user);
+
+        let emergency_msg = ExecuteMsg::EmergencyUnlock { collection: collection.to_string(), token_id: "1".to_string() };
+        assert!(execute(deps.as_mut(), mock_env(), message_info(&user, &[]), emergency_msg.clone()).is_err());
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(600);
+        let response = execute(deps.as_mut(), env, message_info(&user, &[]), emergency_msg).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        // A late ack can't release the token a second time
+        let ack = mock_ibc_packet_ack("channel-0", &unlock_packet, IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        let response = ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        assert!(response.messages.is_empty());
+    }
+
+    #[test]
+    fn test_relock_in_the_block_of_an_emergency_unlock() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.emergency_unlock_grace = Some(600);
+        let (mut deps, _, user) = setup(msg);
+        let collection = Addr::unchecked(COLLECTION);
+        let lock_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: "1".to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        mock_lock(&mut deps, &user, "1");
+
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: COLLECTION.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg.clone()).unwrap();
+
+        // The token is reclaimed and locked again within the same block
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(600);
+        let emergency_msg = ExecuteMsg::EmergencyUnlock { collection: COLLECTION.to_string(), token_id: "1".to_string() };
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), emergency_msg).unwrap();
+        execute(deps.as_mut(), env.clone(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        execute(deps.as_mut(), env.clone(), message_info(&collection, &[]), lock_msg.clone()).unwrap();
+
+        // The timeout of the new lock sends the token back
+        let timeout = mock_ibc_packet_timeout("channel-0", &last_pending_packet(&deps, &user)).unwrap();
+        let response = ibc_packet_timeout(deps.as_mut(), env.clone(), timeout).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(USERS_DATA.load(deps.as_ref().storage, user.clone()).unwrap().lock_credits, 1);
+
+        // The ack of the new lock records it in the ledger
+        execute(deps.as_mut(), env.clone(), message_info(&collection, &[]), lock_msg).unwrap();
+        let lock_packet = last_pending_packet(&deps, &user);
+        let ack = mock_ibc_packet_ack("channel-0", &lock_packet, IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), ack).unwrap();
+        assert_eq!(LOCKED_TOKENS.load(deps.as_ref().storage, (COLLECTION.to_string(), "1".to_string())).unwrap().owner, user);
+
+        // Once unlocked again, the token no longer reports the past release
+        env.block.time = env.block.time.plus_seconds(600);
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), unlock_msg).unwrap();
+        let ack = mock_ibc_packet_ack("channel-0", &last_pending_packet(&deps, &user), IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), ack).unwrap();
+        let status_msg = QueryMsg::GetTokenStatus { user: user.clone(), collection: COLLECTION.to_string(), token_id: "1".to_string() };
+        let status: TokenStatus = from_json(query(deps.as_ref(), env, status_msg).unwrap()).unwrap();
+        assert_eq!(status, TokenStatus::Unlocked);
+    }
+
+    #[test]
+    fn test_batch_lock_and_unlock_partial_results() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION.to_string();
+
+        let owner = user.to_string();
+        deps.querier.update_wasm(move |_| SystemResult::Ok(ContractResult::Ok(to_json_binary(&OwnerOfResponse { owner: owner.clone(), approvals: vec![] }).unwrap())));
+
+        // Two tokens are pulled from the wallet and locked with a single packet
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(200_000, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        let tokens = vec![(collection.clone(), "1".to_string()), (collection.clone(), "2".to_string())];
+        let response = execute(deps.as_mut(), mock_env(), message_info(&user, &[]), ExecuteMsg::LockTokens { tokens: tokens.clone(), native_address: None }).unwrap();
+        assert_eq!(response.messages.len(), 2);
+
+        // The host rejects the second token only, it is sent back and its credit refunded
+        let lock_packet = last_pending_packet(&deps, &user);
+        let batch_ack = AckMessage::BatchResult { results: vec![
+            BatchItemResult { collection: collection.clone(), token_id: "1".to_string(), error: None },
+            BatchItemResult { collection: collection.clone(), token_id: "2".to_string(), error: Some("already locked".to_string()) },
+        ] };
+        let ack = mock_ibc_packet_ack("channel-0", &lock_packet, IbcAcknowledgement::encode_json(&batch_ack).unwrap()).unwrap();
+        let response = ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        let user_data = USERS_DATA.load(deps.as_ref().storage, user.clone()).unwrap();
+        assert_eq!(user_data.lock_credits, 1);
+        assert!(LOCKED_TOKENS.has(deps.as_ref().storage, (collection.clone(), "1".to_string())));
+        assert!(!LOCKED_TOKENS.has(deps.as_ref().storage, (collection.clone(), "2".to_string())));
+
+        // Unlocking a batch with a token that is not locked is rejected
+        assert!(execute(deps.as_mut(), mock_env(), message_info(&user, &[]), ExecuteMsg::UnlockTokens { tokens, native_address: None }).is_err());
+
+        let unlock_msg = ExecuteMsg::UnlockTokens { tokens: vec![(collection.clone(), "1".to_string())], native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let unlock_packet = last_pending_packet(&deps, &user);
+        let ack = mock_ibc_packet_ack("channel-0", &unlock_packet, IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        let response = ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        assert_eq!(response.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_ics721_channel_lock_and_return() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+        let collection = COLLECTION.to_string();
+
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), default_instantiate_msg()).unwrap();
+
+        // The channel speaks ICS-721 when the counterparty proposes its version
+        let response = ibc_channel_open(deps.as_mut(), mock_env(), mock_ibc_channel_open_try("channel-0", IbcOrder::Unordered, ICS721_VERSION)).unwrap();
+        assert_eq!(response.unwrap().version, ICS721_VERSION);
+        ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_ack("channel-0", IbcOrder::Unordered, ICS721_VERSION)).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::BindChannel { channel_id: "channel-0".to_string(), host: "osmosis".to_string(), fee_enabled: None }).unwrap();
+        assert_eq!(CHANNELS.load(deps.as_ref().storage, "channel-0".to_string()).unwrap().protocol, ChannelProtocol::Ics721);
+
+        // The lock is sent as a transfer to the user address on the host, the ack settles the pending request
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: "1".to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), message_info(&Addr::unchecked(&collection), &[]), nft_msg).unwrap();
+
+        let host = HostInfo { label: "osmosis".to_string(), chain_prefix: "osmo".to_string(), height_timeout: None };
+        let transfer: NonFungibleTokenPacketData = from_json(ics721::encode_request(&last_pending_packet(&deps, &user), &host).unwrap()).unwrap();
+        assert_eq!(transfer.class_id, collection);
+        assert_eq!(transfer.token_ids, vec!["1".to_string()]);
+        assert!(transfer.receiver.starts_with("osmo1"));
+
+        let ack = mock_ibc_packet_ack("channel-0", &transfer, IbcAcknowledgement::encode_json(&Ics721Ack::Result(Binary::from(vec![1]))).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        assert!(PENDING_PACKETS_REQUESTS.prefix(user.clone()).is_empty(deps.as_ref().storage));
+        assert_eq!(LOCKED_TOKENS.load(deps.as_ref().storage, (collection.clone(), "1".to_string())).unwrap().owner, user);
+
+        // Unlocks are initiated by the host, not by the satellite
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.clone(), token_id: "1".to_string(), native_address: None };
+        assert!(execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).is_err());
+
+        // Vouchers that don't come from this channel are refused
+        let mut voucher = NonFungibleTokenPacketData {
+            class_id: collection.clone(),
+            class_uri: None,
+            class_data: None,
+            token_ids: vec!["1".to_string()],
+            token_uris: None,
+            token_data: None,
+            sender: transfer.receiver.clone(),
+            receiver: user.to_string(),
+            memo: None,
+        };
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &voucher).unwrap()).unwrap();
+        assert!(matches!(from_json(response.acknowledgement.unwrap()).unwrap(), Ics721Ack::Error(_)));
+
+        // The returned voucher releases the token to the receiver
+        voucher.class_id = format!("their-port/channel-1234/{}", collection);
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &voucher).unwrap()).unwrap();
+        assert!(matches!(from_json(response.acknowledgement.unwrap()).unwrap(), Ics721Ack::Result(_)));
+        assert_eq!(response.messages.len(), 1);
+        assert!(!LOCKED_TOKENS.has(deps.as_ref().storage, (collection.clone(), "1".to_string())));
+    }
+
+    #[test]
+    fn test_ics721_return_follows_min_lock_duration() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+        let collection = COLLECTION.to_string();
+
+        let mut msg = default_instantiate_msg();
+        msg.collections_info[0].min_lock_duration = Some(1_000);
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg).unwrap();
+        ibc_channel_open(deps.as_mut(), mock_env(), mock_ibc_channel_open_try("channel-0", IbcOrder::Unordered, ICS721_VERSION)).unwrap();
+        ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_ack("channel-0", IbcOrder::Unordered, ICS721_VERSION)).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::BindChannel { channel_id: "channel-0".to_string(), host: "osmosis".to_string(), fee_enabled: None }).unwrap();
+        add_locked_token(deps.as_mut().storage, &user, &collection, "1", mock_env().block.time.seconds(), None).unwrap();
+
+        let voucher = NonFungibleTokenPacketData {
+            class_id: format!("their-port/channel-1234/{}", collection),
+            class_uri: None,
+            class_data: None,
+            token_ids: vec!["1".to_string()],
+            token_uris: None,
+            token_data: None,
+            sender: "osmo1sender".to_string(),
+            receiver: user.to_string(),
+            memo: None,
+        };
+
+        // A return before the minimum lock duration is refused, the token stays locked
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &voucher).unwrap()).unwrap();
+        assert!(matches!(from_json(response.acknowledgement.unwrap()).unwrap(), Ics721Ack::Error(_)));
+        assert!(LOCKED_TOKENS.has(deps.as_ref().storage, (collection.clone(), "1".to_string())));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(1_000);
+        let response = ibc_packet_receive(deps.as_mut(), env, mock_ibc_packet_recv("channel-0", &voucher).unwrap()).unwrap();
+        assert!(matches!(from_json(response.acknowledgement.unwrap()).unwrap(), Ics721Ack::Result(_)));
+        assert!(!LOCKED_TOKENS.has(deps.as_ref().storage, (collection, "1".to_string())));
+    }
+
+    #[test]
+    fn test_protocol_version_negotiation() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), default_instantiate_msg()).unwrap();
+
+        // The highest version shared with the counterparty is picked on try
+        let proposal = format!("{},{}", IBC_APP_VERSION_V1, IBC_APP_VERSION_V2);
+        let response = ibc_channel_open(deps.as_mut(), mock_env(), mock_ibc_channel_open_try("channel-0", IbcOrder::Unordered, &proposal)).unwrap();
+        assert_eq!(response.unwrap().version, IBC_APP_VERSION_V2);
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), mock_ibc_channel_open_try("channel-1", IbcOrder::Unordered, "gamefi-satellite-protocol-v9")).is_err());
+
+        // On init every version is proposed, the one picked by the counterparty is stored on connect
+        let response = ibc_channel_open(deps.as_mut(), mock_env(), mock_ibc_channel_open_init("channel-1", IbcOrder::Unordered, "")).unwrap();
+        assert_eq!(response.unwrap().version, format!("{},{}", IBC_APP_VERSION_V2, IBC_APP_VERSION_V1));
+        ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_ack("channel-1", IbcOrder::Unordered, IBC_APP_VERSION_V1)).unwrap();
+        assert_eq!(CHANNELS.load(deps.as_ref().storage, "channel-1".to_string()).unwrap().protocol, ChannelProtocol::SatelliteV1);
+
+        // Packets and acks of a v2 channel travel in a versioned envelope
+        ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_confirm("channel-0", IbcOrder::Unordered, IBC_APP_VERSION_V2)).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::BindChannel { channel_id: "channel-0".to_string(), host: "osmosis".to_string(), fee_enabled: None }).unwrap();
+        mock_lock_with_ack(&mut deps, &user, "1", |packet| VersionedPayload { version: 2, payload: packet }, |ack| IbcAcknowledgement::encode_json(&VersionedPayload { version: 2, payload: ack }).unwrap());
+        assert!(PENDING_PACKETS_REQUESTS.prefix(user.clone()).is_empty(deps.as_ref().storage));
+        assert_eq!(LOCKED_TOKENS.idx.owner.prefix(user.clone()).keys(deps.as_ref().storage, None, None, Order::Ascending).count(), 1);
+
+        let sync = VersionedPayload { version: 2, payload: IbcPacketIncoming {
+            request_id: 1,
+            timestamp: 1_000_000,
+            packet_type: IncomingPacketType::SyncState { user: user.clone(), host_height: None },
+        } };
+        let response = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-0", &sync).unwrap()).unwrap();
+        let ack: VersionedPayload<AckMessage> = from_json(response.acknowledgement.unwrap()).unwrap();
+        assert_eq!(ack.version, 2);
+        assert!(matches!(ack.payload, AckMessage::UserState { lock_credits: 0, .. }));
+    }
+
+    #[test]
+    fn test_relayer_fees_funded_by_credit_revenue() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.relayer_fees = Some(RelayerFeeSettings {
+            fees: vec![RelayerFee { denom: "uosmo".to_string(), receive_fee: Uint128::new(10_000), ack_fee: Uint128::new(5_000), timeout_fee: Uint128::new(5_000) }],
+            revenue_share: 50,
+        });
+        let (mut deps, admin, user) = setup(msg.clone());
+
+        // A denom can only have one fee
+        let mut invalid_msg = msg.clone();
+        let fee = invalid_msg.ibc_settings.relayer_fees.as_ref().unwrap().fees[0].clone();
+        invalid_msg.ibc_settings.relayer_fees.as_mut().unwrap().fees.push(fee);
+        assert!(instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), invalid_msg).is_err());
+
+        // Purchases in a denom without relayer fees don't feed any pool
+        let mut other_denom_msg = msg;
+        other_denom_msg.ibc_settings.relayer_fees.as_mut().unwrap().fees[0].denom = "uatom".to_string();
+        let (mut other_deps, _, _) = setup(other_denom_msg);
+        execute(other_deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let pool: Vec<Coin> = from_json(query(other_deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert!(pool.is_empty());
+
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::BindChannel { channel_id: "channel-0".to_string(), host: "osmosis".to_string(), fee_enabled: Some(true) }).unwrap();
+
+        // Half of the credit price is set aside, every lock pays the relayers of its packet from it,
+        // the timeout fee no relayer earned comes back with the ack
+        mock_lock(&mut deps, &user, "1");
+        let pool: Vec<Coin> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert_eq!(pool, coins(35_000, "uosmo"));
+
+        // Without a fee enabled channel the pool is left untouched
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::BindChannel { channel_id: "channel-0".to_string(), host: "osmosis".to_string(), fee_enabled: None }).unwrap();
+        mock_lock(&mut deps, &user, "2");
+        let pool: Vec<Coin> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert_eq!(pool, coins(85_000, "uosmo"));
+
+        // A timed out packet gives back its receive and ack fees
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::BindChannel { channel_id: "channel-0".to_string(), host: "osmosis".to_string(), fee_enabled: Some(true) }).unwrap();
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: COLLECTION.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let pool: Vec<Coin> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert_eq!(pool, coins(65_000, "uosmo"));
+
+        let timeout = mock_ibc_packet_timeout("channel-0", &last_pending_packet(&deps, &user)).unwrap();
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout).unwrap();
+        let pool: Vec<Coin> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert_eq!(pool, coins(80_000, "uosmo"));
+
+        // The fees of a packet stranded on a closed channel all come back with its recovery
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: "3".to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), message_info(&Addr::unchecked(COLLECTION), &[]), nft_msg).unwrap();
+        let pool: Vec<Coin> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert_eq!(pool, coins(110_000, "uosmo"));
+
+        ibc_channel_close(deps.as_mut(), mock_env(), mock_ibc_channel_close_init("channel-0", IbcOrder::Unordered, "gamefi-satellite-protocol-v1")).unwrap();
+        let recover_msg = ExecuteMsg::RecoverClosedChannel { channel_id: "channel-0".to_string(), limit: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), recover_msg).unwrap();
+        let pool: Vec<Coin> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRelayerFeePool {}).unwrap()).unwrap();
+        assert_eq!(pool, coins(130_000, "uosmo"));
+    }
+
+    #[test]
+    fn test_hybrid_timeout_recorded_on_pending_request() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.timeout_kind = TimeoutKind::Both;
+        msg.ibc_settings.ping_interval = Some(60);
+        msg.hosts[0].height_timeout = Some(HeightTimeout { revision: 1, height_offset: 100, max_report_age: 600 });
+        let (mut deps, admin, user) = setup(msg.clone());
+        let env = mock_env();
+
+        // Height timeouts can't be used without a height setting on every host
+        msg.hosts[0].height_timeout = None;
+        assert!(instantiate(deps.as_mut(), env.clone(), message_info(&admin, &[]), msg).is_err());
+
+        execute(deps.as_mut(), env.clone(), message_info(&user, &coins(200_000, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        let nft_msg = |token_id: &str| ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: token_id.to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        let collection = Addr::unchecked(COLLECTION);
+
+        // The height of the host is unknown until it reports it
+        assert!(execute(deps.as_mut(), env.clone(), message_info(&collection, &[]), nft_msg("1")).is_err());
+
+        let sync = IbcPacketIncoming {
+            request_id: 1,
+            timestamp: 1_000_000,
+            packet_type: IncomingPacketType::SyncState { user: user.clone(), host_height: Some(5_000) },
+        };
+        ibc_packet_receive(deps.as_mut(), env.clone(), mock_ibc_packet_recv("channel-0", &sync).unwrap()).unwrap();
+
+        // An older report delivered late doesn't lower the known height
+        let late_sync = IbcPacketIncoming {
+            request_id: 2,
+            timestamp: 1_000_000,
+            packet_type: IncomingPacketType::SyncState { user: user.clone(), host_height: Some(4_000) },
+        };
+        ibc_packet_receive(deps.as_mut(), env.clone(), mock_ibc_packet_recv("channel-0", &late_sync).unwrap()).unwrap();
+
+        let response = execute(deps.as_mut(), env.clone(), message_info(&collection, &[]), nft_msg("1")).unwrap();
+        assert!(response.attributes.iter().any(|attribute| attribute.key == "lock timeout" && attribute.value.contains("height 1-")));
+
+        // The timeout actually used is kept with the pending request
+        let request_id = last_pending_packet(&deps, &user).request_id;
+        let pending: PendingPacket = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetPendingRequest { user: user.clone(), request_id }).unwrap()).unwrap();
+        assert_eq!(pending.timeout.block(), Some(IbcTimeoutBlock { revision: 1, height: 5_100 }));
+        assert_eq!(pending.timeout.timestamp(), Some(env.block.time.plus_seconds(300)));
+
+        // A stale report is refused, until a pong brings a fresh height
+        let mut env = env;
+        env.block.time = env.block.time.plus_seconds(601);
+        execute(deps.as_mut(), env.clone(), message_info(&user, &coins(200_000, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        assert!(execute(deps.as_mut(), env.clone(), message_info(&collection, &[]), nft_msg("2")).is_err());
+
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), ExecuteMsg::Ping { host: "osmosis".to_string() }).unwrap();
+        let ping = IbcPacketOutgoing {
+            packet_type: PacketType::Ping { sender: user.clone() },
+            chain_prefix: "osmo".to_string(),
+            timestamp: env.block.time.seconds(),
+            request_id: request_id + 1,
+        };
+        let pong = mock_ibc_packet_ack("channel-0", &ping, IbcAcknowledgement::encode_json(&AckMessage::Pong { host_height: 5_200 }).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), pong).unwrap();
+
+        execute(deps.as_mut(), env.clone(), message_info(&collection, &[]), nft_msg("2")).unwrap();
+        let pending = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), last_pending_packet(&deps, &user).request_id)).unwrap();
+        assert_eq!(pending.timeout.block(), Some(IbcTimeoutBlock { revision: 1, height: 5_300 }));
+    }
+
+    #[test]
+    fn test_ping_tracks_relayer_liveness() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.emergency_unlock_grace = Some(600);
+        msg.ibc_settings.ping_interval = Some(60);
+        let (mut deps, _, user) = setup(msg);
+        let collection = COLLECTION;
+
+        mock_lock(&mut deps, &user, "1");
+
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+
+        // Anyone can ping, at a limited rate
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(100);
+        let pinger = deps.api.addr_make("pinger");
+        execute(deps.as_mut(), env.clone(), message_info(&pinger, &[]), ExecuteMsg::Ping { host: "osmosis".to_string() }).unwrap();
+        assert!(execute(deps.as_mut(), env.clone(), message_info(&pinger, &[]), ExecuteMsg::Ping { host: "osmosis".to_string() }).is_err());
+
+        let ping = IbcPacketOutgoing {
+            packet_type: PacketType::Ping { sender: pinger.clone() },
+            chain_prefix: "osmo".to_string(),
+            timestamp: env.block.time.seconds(),
+            request_id: 3,
+        };
+
+        // An error ack is relayed, but doesn't prove the host alive
+        let error_ack = mock_ibc_packet_ack("channel-0", &ping, IbcAcknowledgement::encode_json(&AckMessage::Error { error: "host failure".to_string() }).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), error_ack).unwrap();
+        let health: ChannelHealth = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetChannelHealth { channel_id: "channel-0".to_string() }).unwrap()).unwrap();
+        assert_eq!(health.last_error_ack_at, Some(env.block.time.seconds()));
+        assert_eq!(health.last_ack_at, Some(mock_env().block.time.seconds()));
+
+        let ack = mock_ibc_packet_ack("channel-0", &ping, IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), ack).unwrap();
+
+        let health: ChannelHealth = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetChannelHealth { channel_id: "channel-0".to_string() }).unwrap()).unwrap();
+        assert_eq!(health.last_ping_at, Some(env.block.time.seconds()));
+        assert_eq!(health.last_ack_at, Some(env.block.time.seconds()));
+
+        // The relayer is alive, the unlock will be relayed or timed out, no emergency unlock yet
+        let emergency_msg = ExecuteMsg::EmergencyUnlock { collection: collection.to_string(), token_id: "1".to_string() };
+        env.block.time = mock_env().block.time.plus_seconds(600);
+        assert!(execute(deps.as_mut(), env.clone(), message_info(&user, &[]), emergency_msg.clone()).is_err());
+
+        // No ack for a whole grace period, the relayer is considered down
+        env.block.time = mock_env().block.time.plus_seconds(700);
+        execute(deps.as_mut(), env, message_info(&user, &[]), emergency_msg).unwrap();
+    }
+
+    #[test]
+    fn test_retry_timed_out_unlock() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.retry_backoff = Some(2);
+        let (mut deps, admin, user) = setup(msg.clone());
+        let collection = COLLECTION;
+        let env = mock_env();
+
+        // A backoff must grow the timeout of every retry
+        msg.ibc_settings.retry_backoff = Some(1);
+        assert!(instantiate(deps.as_mut(), env.clone(), message_info(&admin, &[]), msg).is_err());
+
+        mock_lock(&mut deps, &user, "1");
+
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), unlock_msg).unwrap();
+        let unlock_packet = last_pending_packet(&deps, &user);
+        let request_id = unlock_packet.request_id;
+
+        // A request still in flight can't be retried
+        let retry_msg = ExecuteMsg::RetryUnlock { request_id };
+        assert!(execute(deps.as_mut(), env.clone(), message_info(&user, &[]), retry_msg.clone()).is_err());
+
+        // The timed out request is kept, and sent again with a backed off timeout
+        let timeout = mock_ibc_packet_timeout("channel-0", &unlock_packet).unwrap();
+        ibc_packet_timeout(deps.as_mut(), env.clone(), timeout).unwrap();
+        let pending = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), request_id)).unwrap();
+        assert_eq!(pending.status, PendingStatus::TimedOut);
+
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), retry_msg).unwrap();
+        let pending = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), request_id)).unwrap();
+        assert_eq!(pending.status, PendingStatus::InFlight);
+        assert_eq!(pending.timeout.timestamp(), Some(env.block.time.plus_seconds(600)));
+
+        let ack = mock_ibc_packet_ack("channel-0", &pending.packet, IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        let response = ibc_packet_ack(deps.as_mut(), env.clone(), ack).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        // Both packets are traced under the same request
+        let attempts: Vec<RequestAttempt> = from_json(query(deps.as_ref(), env, QueryMsg::GetRequestAttempts { user: user.clone(), request_id }).unwrap()).unwrap();
+        let outcomes: Vec<AttemptOutcome> = attempts.into_iter().map(|attempt| attempt.outcome).collect();
+        assert_eq!(outcomes, vec![AttemptOutcome::TimedOut, AttemptOutcome::Acknowledged]);
+    }
+
+    #[test]
+    fn test_migrate_users_data_to_token_ledger() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+        let collection = COLLECTION.to_string();
+
+        // Storage written by 0.2.0, the locked tokens are kept in the user data
+        cw2::set_contract_version(deps.as_mut().storage, "crates.io:gamefi_satellite", "0.2.0").unwrap();
+        let legacy_state = serde_json::json!({
+            "collections_info": [{ "address": collection }],
+            "admin": admin,
+            "ibc_settings": { "timeout": 300, "max_timeouts": 3 },
+            "host_chain_prefix": "osmo",
+            "lock_credit_settings": { "token": { "denom": "uosmo", "amount": "100000" }, "credit_per_lock": 1 }
+        });
+        deps.storage.set(b"state", &serde_json::to_vec(&legacy_state).unwrap());
+        let legacy_users_data: Map<Addr, serde_json::Value> = Map::new("users_data");
+        legacy_users_data.save(deps.as_mut().storage, user.clone(), &serde_json::json!({
+            "address": user,
+            "locked_tokens": { collection.clone(): ["1", "2"] },
+            "last_lock": 1_000,
+            "lock_credits": 4
+        })).unwrap();
+
+        let legacy_timeouts: Map<(String, Addr), u8> = Map::new("timed_out_unlock_requests");
+        legacy_timeouts.save(deps.as_mut().storage, ("2".to_string(), user.clone()), &2).unwrap();
+
+        // Only the storage of this contract, at a known version, can be migrated
+        cw2::set_contract_version(deps.as_mut().storage, "crates.io:other_contract", "0.2.0").unwrap();
+        assert!(migrate(deps.as_mut(), mock_env(), MigrateMsg {}).is_err());
+        cw2::set_contract_version(deps.as_mut().storage, "crates.io:gamefi_satellite", "0.1.0").unwrap();
+        assert!(migrate(deps.as_mut(), mock_env(), MigrateMsg {}).is_err());
+
+        cw2::set_contract_version(deps.as_mut().storage, "crates.io:gamefi_satellite", "0.2.0").unwrap();
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+        // The credit token becomes the single price
+        let state: State = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetState {}).unwrap()).unwrap();
+        assert_eq!(state.lock_credit_settings.prices, vec![CreditPrice { asset: PaymentAsset::Native { denom: "uosmo".to_string() }, amount: Uint128::new(100_000), tiers: vec![] }]);
+
+        // Every locked token is moved to the ledger, the user data keeps the account only
+        let locked_token = LOCKED_TOKENS.load(deps.as_ref().storage, (collection.clone(), "2".to_string())).unwrap();
+        assert_eq!(locked_token, LockedToken { owner: user.clone(), locked_at: 1_000, native_address: None });
+
+        let user_data: UserDataResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetUserData { address: user.to_string() }).unwrap()).unwrap();
+        assert_eq!(user_data.lock_credits, 4);
+        assert_eq!(user_data.locked_tokens, HashMap::from([(collection.clone(), vec!["1".to_string(), "2".to_string()])]));
+
+        // Timeout counters are scoped to the collection of the locked token
+        let unlock_timeouts: u8 = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetUnlockTimeouts { collection, token_id: "2".to_string(), user }).unwrap()).unwrap();
+        assert_eq!(unlock_timeouts, 2);
+    }
+
+    #[test]
+    fn test_token_lock_query_without_owner() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION.to_string();
+        let token_lock_query = QueryMsg::GetTokenLock { collection: collection.clone(), token_id: "1".to_string() };
+
+        let token_lock: TokenLockResponse = from_json(query(deps.as_ref(), mock_env(), token_lock_query.clone()).unwrap()).unwrap();
+        assert_eq!(token_lock.status, TokenStatus::Unlocked);
+        assert_eq!(token_lock.owner, None);
+
+        // The owner and its address on the host are found from the token alone
+        mock_lock(&mut deps, &user, "1");
+        let token_lock: TokenLockResponse = from_json(query(deps.as_ref(), mock_env(), token_lock_query.clone()).unwrap()).unwrap();
+        assert_eq!(token_lock.status, TokenStatus::Locked);
+        assert_eq!(token_lock.owner, Some(user.clone()));
+        assert_eq!(token_lock.locked_at, Some(mock_env().block.time.seconds()));
+        assert!(token_lock.host_address.unwrap().starts_with("osmo1"));
+
+        let unlock_msg = ExecuteMsg::UnlockToken { collection, token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let token_lock: TokenLockResponse = from_json(query(deps.as_ref(), mock_env(), token_lock_query).unwrap()).unwrap();
+        assert_eq!(token_lock.status, TokenStatus::UnlockPending);
+        assert_eq!(token_lock.pending_request_id, Some(last_pending_packet(&deps, &user).request_id));
+    }
+
+    #[test]
+    fn test_token_status_tracked_per_token() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION;
+        let token_status = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, token_id: &str| -> TokenStatus {
+            let query_msg = QueryMsg::GetTokenStatus { user: user.clone(), collection: collection.to_string(), token_id: token_id.to_string() };
+            from_json(query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap()
+        };
+
+        mock_lock(&mut deps, &user, "1");
+
+        // A pending lock on another token doesn't change the status of the locked one
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: "2".to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), message_info(&Addr::unchecked(collection), &[]), nft_msg).unwrap();
+        assert_eq!(token_status(&deps, "1"), TokenStatus::Locked);
+        assert_eq!(token_status(&deps, "2"), TokenStatus::LockPending);
+        assert_eq!(token_status(&deps, "3"), TokenStatus::Unlocked);
+
+        // A timed out unlock reports the consecutive timeouts of the token
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        assert_eq!(token_status(&deps, "1"), TokenStatus::UnlockPending);
+
+        let timeout = mock_ibc_packet_timeout("channel-0", &last_pending_packet(&deps, &user)).unwrap();
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout).unwrap();
+        assert_eq!(token_status(&deps, "1"), TokenStatus::UnlockTimedOut { count: 1 });
+        assert_eq!(token_status(&deps, "2"), TokenStatus::LockPending);
+    }
+
+    #[test]
+    fn test_concurrent_unlocks_of_a_token_rejected() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION;
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        let batch_unlock_msg = ExecuteMsg::UnlockTokens { tokens: vec![(collection.to_string(), "1".to_string())], native_address: None };
+
+        mock_lock(&mut deps, &user, "1");
+
+        // A second unlock, single or batched, is refused while the first one is in flight
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg.clone()).unwrap();
+        let error = execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg.clone()).unwrap_err();
+        assert!(error.to_string().contains("already in flight"));
+        assert!(execute(deps.as_mut(), mock_env(), message_info(&user, &[]), batch_unlock_msg.clone()).is_err());
+
+        // An error ack releases the guard
+        let ack = mock_ibc_packet_ack("channel-0", &last_pending_packet(&deps, &user), IbcAcknowledgement::encode_json(&AckMessage::Error { error: "host busy".to_string() }).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), batch_unlock_msg).unwrap();
+
+        // So does a timeout
+        let timeout = mock_ibc_packet_timeout("channel-0", &last_pending_packet(&deps, &user)).unwrap();
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        assert_eq!(PENDING_PACKETS_REQUESTS.prefix(user.clone()).keys(deps.as_ref().storage, None, None, Order::Ascending).count(), 1);
+    }
+
+    #[test]
+    fn test_unlock_keeps_other_tokens_of_a_recoverable_batch() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION.to_string();
+
+        mock_lock(&mut deps, &user, "1");
+        mock_lock(&mut deps, &user, "2");
+
+        // The batch unlock times out and stays recoverable
+        let tokens = vec![(collection.clone(), "1".to_string()), (collection.clone(), "2".to_string())];
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), ExecuteMsg::UnlockTokens { tokens, native_address: None }).unwrap();
+        let batch_packet = last_pending_packet(&deps, &user);
+        let timeout = mock_ibc_packet_timeout("channel-0", &batch_packet).unwrap();
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout).unwrap();
+
+        // Unlocking one of its tokens alone only takes that token out of the batch
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.clone(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+
+        let batch = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), batch_packet.request_id)).unwrap();
+        assert_eq!(batch.packet.packet_type.tokens(), vec![(collection.clone(), "2".to_string())]);
+        let token_lock: TokenLockResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetTokenLock { collection: collection.clone(), token_id: "2".to_string() }).unwrap()).unwrap();
+        assert_eq!(token_lock.pending_request_id, Some(batch_packet.request_id));
+
+        // The retry sends the remaining token only
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), ExecuteMsg::RetryUnlock { request_id: batch_packet.request_id }).unwrap();
+        let batch = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), batch_packet.request_id)).unwrap();
+        assert_eq!(batch.packet.packet_type.tokens(), vec![(collection, "2".to_string())]);
+        assert_eq!(batch.status, PendingStatus::InFlight);
+    }
+
+    #[test]
+    fn test_stale_acks_ignored() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION;
+        let success = || IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap();
+
+        mock_lock(&mut deps, &user, "1");
+
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let unlock_packet = last_pending_packet(&deps, &user);
+
+        // A packet that differs from the one last sent for the request is not applied
+        let older_packet = IbcPacketOutgoing { timestamp: unlock_packet.timestamp - 1, ..unlock_packet.clone() };
+        let response = ibc_packet_ack(deps.as_mut(), mock_env(), mock_ibc_packet_ack("channel-0", &older_packet, success()).unwrap()).unwrap();
+        assert!(response.messages.is_empty());
+        assert_eq!(response.events[0].ty, "ignored_stale_ack");
+        assert!(response.events[0].attributes.iter().any(|attribute| attribute.key == "reason" && attribute.value == "packet_mismatch"));
+        assert!(LOCKED_TOKENS.has(deps.as_ref().storage, (collection.to_string(), "1".to_string())));
+
+        // The matching ack releases the token once, a duplicate finds no pending record
+        let response = ibc_packet_ack(deps.as_mut(), mock_env(), mock_ibc_packet_ack("channel-0", &unlock_packet, success()).unwrap()).unwrap();
+        assert_eq!(response.messages.len(), 1);
+
+        let response = ibc_packet_ack(deps.as_mut(), mock_env(), mock_ibc_packet_ack("channel-0", &unlock_packet, success()).unwrap()).unwrap();
+        assert!(response.messages.is_empty());
+        assert!(response.events[0].attributes.iter().any(|attribute| attribute.key == "reason" && attribute.value == "request_already_settled"));
+    }
+
+    #[test]
+    fn test_unexpected_user_state_ack_makes_request_recoverable() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.emergency_unlock_grace = Some(600);
+        let (mut deps, _, user) = setup(msg);
+        let collection = COLLECTION;
+
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: "1".to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), message_info(&Addr::unchecked(collection), &[]), nft_msg).unwrap();
+        let lock_packet = last_pending_packet(&deps, &user);
+
+        // A state snapshot doesn't settle the lock, the request doesn't stay in flight
+        let user_state = AckMessage::UserState { user: user.clone(), locked_tokens: HashMap::new(), lock_credits: 0 };
+        let ack = mock_ibc_packet_ack("channel-0", &lock_packet, IbcAcknowledgement::encode_json(&user_state).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+
+        let pending = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), lock_packet.request_id)).unwrap();
+        assert_eq!(pending.status, PendingStatus::Recoverable);
+        let attempts: Vec<RequestAttempt> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRequestAttempts { user: user.clone(), request_id: lock_packet.request_id }).unwrap()).unwrap();
+        assert_eq!(attempts.last().unwrap().outcome, AttemptOutcome::InvalidAck);
+        let status_msg = QueryMsg::GetTokenStatus { user: user.clone(), collection: collection.to_string(), token_id: "1".to_string() };
+        let status: TokenStatus = from_json(query(deps.as_ref(), mock_env(), status_msg.clone()).unwrap()).unwrap();
+        assert_eq!(status, TokenStatus::LockRecoverable);
+
+        // The token and its credit can be reclaimed right away
+        let emergency_msg = ExecuteMsg::EmergencyUnlock { collection: collection.to_string(), token_id: "1".to_string() };
+        let response = execute(deps.as_mut(), mock_env(), message_info(&user, &[]), emergency_msg).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(USERS_DATA.load(deps.as_ref().storage, user.clone()).unwrap().lock_credits, 1);
+        let status: TokenStatus = from_json(query(deps.as_ref(), mock_env(), status_msg).unwrap()).unwrap();
+        assert_eq!(status, TokenStatus::EmergencyReleased);
+    }
+
+    #[test]
+    fn test_unlock_timeouts_scoped_to_collection() {
+        let collection = COLLECTION.to_string();
+        let other_collection = "osmo1other".to_string();
+        let mut msg = default_instantiate_msg();
+        msg.collections_info.push(CollectionInfo { address: other_collection.clone(), host: "osmosis".to_string(), min_lock_duration: None, lock_cooldown: None });
+        let (mut deps, admin, user) = setup(msg);
+        let unlock_timeouts = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, collection: &str| -> u8 {
+            let query_msg = QueryMsg::GetUnlockTimeouts { collection: collection.to_string(), token_id: "1".to_string(), user: user.clone() };
+            from_json(query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap()
+        };
+
+        mock_lock(&mut deps, &user, "1");
+        add_locked_token(deps.as_mut().storage, &user, &other_collection, "1", 0, None).unwrap();
+
+        // A timeout on a token only counts for its collection
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.clone(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let timeout = mock_ibc_packet_timeout("channel-0", &last_pending_packet(&deps, &user)).unwrap();
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout).unwrap();
+        assert_eq!(unlock_timeouts(&deps, &collection), 1);
+        assert_eq!(unlock_timeouts(&deps, &other_collection), 0);
+
+        // Only the admin can reset a counter
+        let reset_msg = ExecuteMsg::ResetUnlockTimeouts { collection: collection.clone(), token_id: "1".to_string(), user: user.clone() };
+        assert!(execute(deps.as_mut(), mock_env(), message_info(&user, &[]), reset_msg.clone()).is_err());
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), reset_msg).unwrap();
+        assert_eq!(unlock_timeouts(&deps, &collection), 0);
+    }
+
+    #[test]
+    fn test_prune_expired_pending_requests() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.prune_grace = Some(600);
+        let (mut deps, admin, user) = setup(msg);
+        let collection = COLLECTION;
+        let mut env = mock_env();
+
+        mock_lock(&mut deps, &user, "1");
+        mock_lock(&mut deps, &user, "2");
+
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), unlock_msg).unwrap();
+        let request_id = last_pending_packet(&deps, &user).request_id;
+
+        // A later unlock expires later
+        let mut later_env = env.clone();
+        later_env.block.time = later_env.block.time.plus_seconds(100);
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "2".to_string(), native_address: None };
+        execute(deps.as_mut(), later_env, message_info(&user, &[]), unlock_msg).unwrap();
+
+        // Within the grace period after the timeout, the request is left to the relayer
+        env.block.time = env.block.time.plus_seconds(300 + 599);
+        let response = execute(deps.as_mut(), env.clone(), message_info(&admin, &[]), ExecuteMsg::PrunePending { limit: None }).unwrap();
+        assert!(response.events.is_empty());
+
+        // Past it, anyone can make the request recoverable
+        env.block.time = env.block.time.plus_seconds(1);
+        let response = execute(deps.as_mut(), env.clone(), message_info(&user, &[]), ExecuteMsg::PrunePending { limit: None }).unwrap();
+        assert_eq!(response.events.len(), 1);
+        assert_eq!(response.events[0].ty, "pruned_pending_request");
+
+        let pending = PENDING_PACKETS_REQUESTS.load(deps.as_ref().storage, (user.clone(), request_id)).unwrap();
+        assert_eq!(pending.status, PendingStatus::Recoverable);
+        let status: TokenStatus = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetTokenStatus { user: user.clone(), collection: collection.to_string(), token_id: "1".to_string() }).unwrap()).unwrap();
+        assert_eq!(status, TokenStatus::Locked);
+        let attempts: Vec<RequestAttempt> = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetRequestAttempts { user: user.clone(), request_id }).unwrap()).unwrap();
+        assert_eq!(attempts[0].outcome, AttemptOutcome::Pruned);
+        let history: Vec<HistoryEntry> = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetTokenHistory { collection: collection.to_string(), token_id: "1".to_string(), start_after: None, limit: None }).unwrap()).unwrap();
+        assert_eq!(history.last().unwrap().action, HistoryAction::Pruned);
+
+        // The unlock can be sent again
+        execute(deps.as_mut(), env, message_info(&user, &[]), ExecuteMsg::RetryUnlock { request_id }).unwrap();
+    }
+
+    #[test]
+    fn test_late_ack_after_prune() {
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.prune_grace = Some(600);
+        let (mut deps, admin, user) = setup(msg);
+        let collection = COLLECTION;
+        let mut env = mock_env();
+        let lock_msg = |token_id: &str| ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: token_id.to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+
+        execute(deps.as_mut(), env.clone(), message_info(&user, &coins(200_000, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+
+        execute(deps.as_mut(), env.clone(), message_info(&Addr::unchecked(collection), &[]), lock_msg("1")).unwrap();
+        let first_lock = last_pending_packet(&deps, &user);
+        execute(deps.as_mut(), env.clone(), message_info(&Addr::unchecked(collection), &[]), lock_msg("2")).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(300 + 600);
+        let response = execute(deps.as_mut(), env.clone(), message_info(&admin, &[]), ExecuteMsg::PrunePending { limit: None }).unwrap();
+        assert_eq!(response.events.len(), 2);
+
+        // The host applied the first lock, its late ack still settles the pruned request
+        let ack = mock_ibc_packet_ack("channel-0", &first_lock, IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), ack).unwrap();
+        assert_eq!(LOCKED_TOKENS.load(deps.as_ref().storage, (collection.to_string(), "1".to_string())).unwrap().owner, user);
+        assert!(!PENDING_PACKETS_REQUESTS.has(deps.as_ref().storage, (user.clone(), first_lock.request_id)));
+
+        // The second one is reclaimed with its credit, even with emergency unlocks disabled
+        let emergency_msg = ExecuteMsg::EmergencyUnlock { collection: collection.to_string(), token_id: "2".to_string() };
+        let response = execute(deps.as_mut(), env.clone(), message_info(&user, &[]), emergency_msg).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(USERS_DATA.load(deps.as_ref().storage, user.clone()).unwrap().lock_credits, 1);
+        assert!(PENDING_PACKETS_REQUESTS.is_empty(deps.as_ref().storage));
+
+        // Without a timestamp timeout, the expiry of a packet is unknown and it is never pruned
+        let mut state: State = from_json(query(deps.as_ref(), env.clone(), QueryMsg::GetState {}).unwrap()).unwrap();
+        state.ibc_settings.timeout_kind = TimeoutKind::Height;
+        state.hosts[0].height_timeout = Some(HeightTimeout { revision: 1, height_offset: 100, max_report_age: 600 });
+        let update_msg = ExecuteMsg::UpdateStatePayload { state_changes: UpdateStatePayload { collections_info: None, ibc_settings: Some(state.ibc_settings), admin: None, hosts: Some(state.hosts), lock_credit_settings: None } };
+        execute(deps.as_mut(), env.clone(), message_info(&admin, &[]), update_msg).unwrap();
+        let sync = IbcPacketIncoming {
+            request_id: 1,
+            timestamp: env.block.time.seconds(),
+            packet_type: IncomingPacketType::SyncState { user: user.clone(), host_height: Some(5_000) },
+        };
+        ibc_packet_receive(deps.as_mut(), env.clone(), mock_ibc_packet_recv("channel-0", &sync).unwrap()).unwrap();
+        execute(deps.as_mut(), env.clone(), message_info(&Addr::unchecked(collection), &[]), lock_msg("3")).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(100_000);
+        let response = execute(deps.as_mut(), env, message_info(&admin, &[]), ExecuteMsg::PrunePending { limit: None }).unwrap();
+        assert!(response.events.is_empty());
+    }
+
+    #[test]
+    fn test_history_paginated_by_user_and_token() {
+        let (mut deps, _, user) = setup(default_instantiate_msg());
+        let collection = COLLECTION;
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+
+        mock_lock(&mut deps, &user, "1");
+        mock_lock(&mut deps, &user, "2");
+
+        // A failed, a timed out and a successful unlock of the same token
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg.clone()).unwrap();
+        let ack = mock_ibc_packet_ack("channel-0", &last_pending_packet(&deps, &user), IbcAcknowledgement::encode_json(&AckMessage::Error { error: "host busy".to_string() }).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg.clone()).unwrap();
+        let timeout = mock_ibc_packet_timeout("channel-0", &last_pending_packet(&deps, &user)).unwrap();
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout).unwrap();
+
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), unlock_msg).unwrap();
+        let ack = mock_ibc_packet_ack("channel-0", &last_pending_packet(&deps, &user), IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+
+        let token_history = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, start_after: Option<u64>| -> Vec<HistoryEntry> {
+            let query_msg = QueryMsg::GetTokenHistory { collection: collection.to_string(), token_id: "1".to_string(), start_after, limit: Some(2) };
+            from_json(query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap()
+        };
+
+        let first_page = token_history(&deps, None);
+        assert_eq!(first_page.iter().map(|entry| entry.action.clone()).collect::<Vec<_>>(), vec![
+            HistoryAction::Lock,
+            HistoryAction::UnlockFailed { reason: "host busy".to_string() },
+        ]);
+        let second_page = token_history(&deps, first_page.last().map(|entry| entry.sequence));
+        assert_eq!(second_page.iter().map(|entry| entry.action.clone()).collect::<Vec<_>>(), vec![HistoryAction::UnlockTimedOut, HistoryAction::Unlock]);
+        assert!(token_history(&deps, second_page.last().map(|entry| entry.sequence)).is_empty());
+
+        // The user history holds the entries of every token
+        let user_history: Vec<HistoryEntry> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetUserHistory { user, start_after: None, limit: Some(30) }).unwrap()).unwrap();
+        assert_eq!(user_history.len(), 5);
+        assert_eq!(user_history[1].token_id, "2");
+    }
+
+    #[test]
+    fn test_collection_min_lock_duration_and_cooldown() {
+        let mut msg = default_instantiate_msg();
+        msg.collections_info[0].min_lock_duration = Some(100);
+        msg.collections_info[0].lock_cooldown = Some(1_000);
+        let (mut deps, _, user) = setup(msg);
+        let collection = COLLECTION;
+        let unlock_msg = ExecuteMsg::UnlockToken { collection: collection.to_string(), token_id: "1".to_string(), native_address: None };
+        let mut env = mock_env();
+
+        mock_lock(&mut deps, &user, "1");
+
+        // The token must stay locked for the minimum duration
+        env.block.time = env.block.time.plus_seconds(99);
+        let error = execute(deps.as_mut(), env.clone(), message_info(&user, &[]), unlock_msg.clone()).unwrap_err();
+        assert!(error.to_string().contains("can't be unlocked before"));
+
+        env.block.time = env.block.time.plus_seconds(1);
+        execute(deps.as_mut(), env.clone(), message_info(&user, &[]), unlock_msg).unwrap();
+        let ack = mock_ibc_packet_ack("channel-0", &last_pending_packet(&deps, &user), IbcAcknowledgement::encode_json(&AckMessage::Success {}).unwrap()).unwrap();
+        ibc_packet_ack(deps.as_mut(), env.clone(), ack).unwrap();
+
+        // It can be locked again once the cooldown is elapsed since its previous lock
+        execute(deps.as_mut(), env.clone(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: "1".to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        let error = execute(deps.as_mut(), env.clone(), message_info(&Addr::unchecked(collection), &[]), nft_msg.clone()).unwrap_err();
+        assert!(error.to_string().contains("can't be locked again before"));
+
+        env.block.time = mock_env().block.time.plus_seconds(1_000);
+        execute(deps.as_mut(), env, message_info(&Addr::unchecked(collection), &[]), nft_msg).unwrap();
+    }
+
+    #[test]
+    fn test_purchase_credits_with_price_list() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+        let cw20_token = deps.api.addr_make("cw20_token");
+        let other_cw20 = deps.api.addr_make("other_cw20");
+
+        let mut msg = default_instantiate_msg();
+        msg.lock_credit_settings.prices.push(CreditPrice { asset: PaymentAsset::Native { denom: "uatom".to_string() }, amount: Uint128::new(10_000), tiers: vec![] });
+        msg.lock_credit_settings.prices.push(CreditPrice { asset: PaymentAsset::Cw20 { address: cw20_token.to_string() }, amount: Uint128::new(500), tiers: vec![] });
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg).unwrap();
+
+        // Any native denom of the list pays the credits
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(20_000, "uatom")), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(10_000, "uatom")), ExecuteMsg::GetCredits { amount: 2 }).unwrap_err();
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "ujuno")), ExecuteMsg::GetCredits { amount: 1 }).unwrap_err();
+
+        // A cw20 of the list pays the credits of the sender of the cw20 Send
+        let receive = |sender: &Addr, amount: u128, credits: u16| ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: sender.to_string(),
+            amount: Uint128::new(amount),
+            msg: to_json_binary(&Cw20HookMsg::GetCredits { amount: credits }).unwrap()
+        });
+        execute(deps.as_mut(), mock_env(), message_info(&cw20_token, &[]), receive(&user, 1_500, 3)).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&cw20_token, &[]), receive(&user, 400, 1)).unwrap_err();
+        execute(deps.as_mut(), mock_env(), message_info(&other_cw20, &[]), receive(&user, 1_500, 3)).unwrap_err();
+
+        let user_data: UserDataResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetUserData { address: user.to_string() }).unwrap()).unwrap();
+        assert_eq!(user_data.lock_credits, 5);
+    }
+
+    #[test]
+    fn test_purchase_credits_refunds_overpayment() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+        let cw20_token = deps.api.addr_make("cw20_token");
+        let attribute = |response: &Response, key: &str| response.attributes.iter().find(|attribute| attribute.key == key).unwrap().value.clone();
+
+        let mut msg = default_instantiate_msg();
+        msg.lock_credit_settings.prices.push(CreditPrice { asset: PaymentAsset::Cw20 { address: cw20_token.to_string() }, amount: Uint128::new(500), tiers: vec![] });
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg).unwrap();
+
+        // The excess and the unrelated denoms go back with a single bank message
+        let funds = vec![Coin::new(250_000u128, "uosmo"), Coin::new(7u128, "uzzz")];
+        let response = execute(deps.as_mut(), mock_env(), message_info(&user, &funds), ExecuteMsg::GetCredits { amount: 2 }).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0].msg, CosmosMsg::Bank(BankMsg::Send { to_address: user.to_string(), amount: vec![Coin::new(50_000u128, "uosmo"), Coin::new(7u128, "uzzz")] }));
+        assert_eq!(attribute(&response, "paid"), "250000uosmo,7uzzz");
+        assert_eq!(attribute(&response, "price"), "200000uosmo");
+        assert_eq!(attribute(&response, "refunded"), "50000uosmo,7uzzz");
+
+        // The exact price sends nothing back
+        let response = execute(deps.as_mut(), mock_env(), message_info(&user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        assert!(response.messages.is_empty());
+        assert_eq!(attribute(&response, "refunded"), "0");
+
+        // The excess of a cw20 payment is transferred back
+        let receive_msg = ExecuteMsg::Receive(Cw20ReceiveMsg { sender: user.to_string(), amount: Uint128::new(700), msg: to_json_binary(&Cw20HookMsg::GetCredits { amount: 1 }).unwrap() });
+        let response = execute(deps.as_mut(), mock_env(), message_info(&cw20_token, &[]), receive_msg).unwrap();
+        assert_eq!(response.messages[0].msg, CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: cw20_token.to_string(),
+            msg: to_json_binary(&Cw20ExecuteMsg::Transfer { recipient: user.to_string(), amount: Uint128::new(200) }).unwrap(),
+            funds: vec![]
+        }));
+
+        let user_data: UserDataResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetUserData { address: user.to_string() }).unwrap()).unwrap();
+        assert_eq!(user_data.lock_credits, 4);
+
+        // A balance or a cost that doesn't fit is an error, not a panic
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &coins(1, "uosmo")), ExecuteMsg::GetCredits { amount: u16::MAX }).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &coins(1, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap_err();
+
+        let mut state: State = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetState {}).unwrap()).unwrap();
+        state.lock_credit_settings.prices[0].amount = Uint128::MAX;
+        let update_msg = ExecuteMsg::UpdateStatePayload { state_changes: UpdateStatePayload { collections_info: None, ibc_settings: None, admin: None, hosts: None, lock_credit_settings: Some(state.lock_credit_settings) } };
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), update_msg).unwrap();
+        query(deps.as_ref(), mock_env(), QueryMsg::SimulateCreditPurchase { amount: 2 }).unwrap_err();
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(1, "uosmo")), ExecuteMsg::GetCredits { amount: 2 }).unwrap_err();
+    }
+
+    #[test]
+    fn test_withdraw_fees_split_between_treasury_recipients() {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+        let manager = deps.api.addr_make("manager");
+        let studio = deps.api.addr_make("studio");
+        let dao = deps.api.addr_make("dao");
+
+        let mut msg = default_instantiate_msg();
+        msg.ibc_settings.relayer_fees = Some(RelayerFeeSettings {
+            fees: vec![RelayerFee { denom: "uosmo".to_string(), receive_fee: Uint128::new(1), ack_fee: Uint128::new(1), timeout_fee: Uint128::new(1) }],
+            revenue_share: 10
+        });
+        msg.lock_credit_settings.treasury = Some(TreasurySettings {
+            manager: manager.clone(),
+            splits: vec![
+                TreasurySplit { label: "studio".to_string(), address: studio.clone(), percent: 60 },
+                TreasurySplit { label: "dao".to_string(), address: dao.clone(), percent: 50 }
+            ]
+        });
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg.clone()).unwrap_err();
+
+        msg.lock_credit_settings.treasury.as_mut().unwrap().splits[1].percent = 40;
+        msg.lock_credit_settings.treasury.as_mut().unwrap().splits[1].address = Addr::unchecked("dao");
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg.clone()).unwrap_err();
+
+        msg.lock_credit_settings.treasury.as_mut().unwrap().splits[1].address = dao.clone();
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&user, &coins(300_000, "uosmo")), ExecuteMsg::GetCredits { amount: 3 }).unwrap();
+
+        // Only the admin and the treasury manager can withdraw
+        execute(deps.as_mut(), mock_env(), message_info(&user, &[]), ExecuteMsg::WithdrawFees {}).unwrap_err();
+
+        // The revenue left after the relayer share is split between the recipients
+        let response = execute(deps.as_mut(), mock_env(), message_info(&manager, &[]), ExecuteMsg::WithdrawFees {}).unwrap();
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(response.messages[0].msg, CosmosMsg::Bank(BankMsg::Send { to_address: studio.to_string(), amount: coins(162_000, "uosmo") }));
+        assert_eq!(response.messages[1].msg, CosmosMsg::Bank(BankMsg::Send { to_address: dao.to_string(), amount: coins(108_000, "uosmo") }));
+
+        let revenue: Vec<AssetRevenue> = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetRevenue {}).unwrap()).unwrap();
+        assert_eq!(revenue, vec![AssetRevenue {
+            asset: PaymentAsset::Native { denom: "uosmo".to_string() },
+            collected: Uint128::new(300_000),
+            relayer_share: Uint128::new(30_000),
+            withdrawn: Uint128::new(270_000)
+        }]);
+
+        // Nothing left until the next sale
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), ExecuteMsg::WithdrawFees {}).unwrap_err();
+    }
+
+    #[test]
+    fn test_tiered_and_promotional_credit_prices() {
+        let now = mock_env().block.time.seconds();
+        let uosmo = PaymentAsset::Native { denom: "uosmo".to_string() };
+        let simulate = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, env: Env, amount: u16| -> CreditQuote {
+            let quotes: Vec<CreditQuote> = from_json(query(deps.as_ref(), env, QueryMsg::SimulateCreditPurchase { amount }).unwrap()).unwrap();
+            quotes.into_iter().next().unwrap()
+        };
+
+        let mut msg = default_instantiate_msg();
+        msg.lock_credit_settings.prices[0].tiers = vec![
+            PriceTier { min_amount: 10, amount: Uint128::new(80_000) },
+            PriceTier { min_amount: 5, amount: Uint128::new(90_000) }
+        ];
+        msg.lock_credit_settings.promotions = vec![Promotion { start: now + 100, end: now + 200, discount: 25 }];
+        let (mut deps, _, user) = setup(msg);
+
+        // The quantity picks the highest tier it reaches
+        assert_eq!(simulate(&deps, mock_env(), 4), CreditQuote { asset: uosmo.clone(), unit_price: Uint128::new(100_000), discount: 0, cost: Uint128::new(400_000) });
+        assert_eq!(simulate(&deps, mock_env(), 5).cost, Uint128::new(450_000));
+        assert_eq!(simulate(&deps, mock_env(), 12).cost, Uint128::new(960_000));
+
+        // The simulated cost is exactly what a purchase takes
+        let response = execute(deps.as_mut(), mock_env(), message_info(&user, &coins(500_000, "uosmo")), ExecuteMsg::GetCredits { amount: 5 }).unwrap();
+        assert_eq!(response.messages[0].msg, CosmosMsg::Bank(BankMsg::Send { to_address: user.to_string(), amount: coins(50_000, "uosmo") }));
+
+        // A running promotion discounts the total, until it ends
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(150);
+        assert_eq!(simulate(&deps, env.clone(), 5), CreditQuote { asset: uosmo.clone(), unit_price: Uint128::new(90_000), discount: 25, cost: Uint128::new(337_500) });
+        execute(deps.as_mut(), env.clone(), message_info(&user, &coins(337_500, "uosmo")), ExecuteMsg::GetCredits { amount: 5 }).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(50);
+        assert_eq!(simulate(&deps, env.clone(), 5).discount, 0);
+        execute(deps.as_mut(), env, message_info(&user, &coins(337_500, "uosmo")), ExecuteMsg::GetCredits { amount: 5 }).unwrap_err();
+    }
+
+    #[test]
+    fn test_grant_credits_and_campaigns() {
+        let (mut deps, admin, _) = setup(default_instantiate_msg());
+        let alice = deps.api.addr_make("alice");
+        let bob = deps.api.addr_make("bob");
+        let lock_credits = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, user: &Addr| -> u16 {
+            let user_data: UserDataResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetUserData { address: user.to_string() }).unwrap()).unwrap();
+            user_data.lock_credits
+        };
+        let grant = |recipients: Vec<(&Addr, u64)>, campaign: Option<&str>| ExecuteMsg::GrantCredits {
+            recipients: recipients.into_iter().map(|(user, credits)| (user.to_string(), credits)).collect(),
+            campaign: campaign.map(|name| name.to_string())
+        };
+
+        // The admin credits the players, not itself
+        execute(deps.as_mut(), mock_env(), message_info(&alice, &[]), grant(vec![(&alice, 5)], None)).unwrap_err();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), grant(vec![(&alice, 2), (&bob, 3)], None)).unwrap();
+        assert_eq!(lock_credits(&deps, &alice), 2);
+        assert_eq!(lock_credits(&deps, &bob), 3);
+
+        // Campaign grants are bounded by the budget and the per user cap
+        let create_campaign = ExecuteMsg::CreateCampaign { name: "onboarding".to_string(), budget: 10, per_user_cap: Some(4), expires_at: Some(mock_env().block.time.seconds() + 100) };
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), create_campaign.clone()).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), create_campaign).unwrap_err();
+
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), grant(vec![(&alice, 3), (&bob, 4)], Some("onboarding"))).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), grant(vec![(&alice, 2)], Some("onboarding"))).unwrap_err();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), grant(vec![(&alice, 1), (&admin, 4)], Some("onboarding"))).unwrap_err();
+        assert_eq!(lock_credits(&deps, &alice), 5);
+
+        let campaign: Campaign = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetCampaign { name: "onboarding".to_string() }).unwrap()).unwrap();
+        assert_eq!((campaign.granted, campaign.recipients), (7, 2));
+        let alice_grant: u64 = from_json(query(deps.as_ref(), mock_env(), QueryMsg::GetCampaignGrant { name: "onboarding".to_string(), user: alice.clone() }).unwrap()).unwrap();
+        assert_eq!(alice_grant, 3);
+
+        // Overflowing grants are rejected
+        let unbounded_campaign = ExecuteMsg::CreateCampaign { name: "unbounded".to_string(), budget: u64::MAX, per_user_cap: None, expires_at: None };
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), unbounded_campaign).unwrap();
+        execute(deps.as_mut(), mock_env(), message_info(&admin, &[]), grant(vec![(&alice, u64::MAX), (&alice, 1)], Some("unbounded"))).unwrap_err();
+
+        // No grant once the campaign expired
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(100);
+        execute(deps.as_mut(), env, message_info(&admin, &[]), grant(vec![(&alice, 1)], Some("onboarding"))).unwrap_err();
+    }
+
+    //Buy a credit, lock the token and acknowledge the lock
+    fn mock_lock(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, user: &Addr, token_id: &str) {
+        mock_lock_with_ack(deps, user, token_id, |packet| packet, |ack| IbcAcknowledgement::encode_json(&ack).unwrap());
+    }
+
+    //Same as mock_lock, the packet and the ack are encoded as the channel protocol expects
+    fn mock_lock_with_ack<T: Serialize>(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        user: &Addr,
+        token_id: &str,
+        encode_packet: impl Fn(IbcPacketOutgoing) -> T,
+        encode_ack: impl Fn(AckMessage) -> IbcAcknowledgement,
+    ) {
+        let collection = Addr::unchecked(COLLECTION);
+
+        execute(deps.as_mut(), mock_env(), message_info(user, &coins(100_000, "uosmo")), ExecuteMsg::GetCredits { amount: 1 }).unwrap();
+        let nft_msg = ExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+            sender: user.to_string(),
+            token_id: token_id.to_string(),
+            msg: to_json_binary(&NftReceiveMsg::LockNft { remote_recipient: None }).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), message_info(&collection, &[]), nft_msg).unwrap();
+
+        let lock_packet = encode_packet(last_pending_packet(deps, user));
+        let ack = mock_ibc_packet_ack("channel-0", &lock_packet, encode_ack(AckMessage::Success {})).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+    }
+
+    fn last_pending_packet(deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, user: &Addr) -> IbcPacketOutgoing {
+        PENDING_PACKETS_REQUESTS
+            .prefix(user.clone())
+            .range(deps.as_ref().storage, None, None, Order::Descending)
+            .next()
+            .unwrap()
+            .unwrap()
+            .1
+            .packet
+    }
+
+    fn open_channel(storage: &mut dyn Storage, channel_id: &str, host: Option<&str>) {
+        CHANNELS.save(storage, channel_id.to_string(), &ChannelInfo {
+            channel_id: channel_id.to_string(),
+            finalized: true,
+            opened_at: 1_000_000,
+            host: host.map(|host| host.to_string()),
+            closed_at: None,
+            protocol: ChannelProtocol::SatelliteV1,
+            fee_enabled: false,
+        }).unwrap();
+
+        if let Some(host) = host {
+            HOST_CHANNELS.save(storage, host.to_string(), &channel_id.to_string()).unwrap();
+        }
+    }
+
     fn mock_app() -> App {
         App::default()
     }
@@ -107,25 +1448,52 @@ mod test
         Box::new(contract)
     }
 
+    //Instantiate the contract and bind channel-0 to the osmosis host, returns the admin and a user
+    fn setup(msg: InstantiateMsg) -> (OwnedDeps<MockStorage, MockApi, MockQuerier>, Addr, Addr) {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        let user = deps.api.addr_make("user");
+
+        instantiate(deps.as_mut(), mock_env(), message_info(&admin, &[]), msg).unwrap();
+        open_channel(deps.as_mut().storage, "channel-0", Some("osmosis"));
+
+        (deps, admin, user)
+    }
+
     fn default_instantiate_msg() -> InstantiateMsg {
-        let mut collections = Vec::new();
-        collections.push(CollectionInfo {
-            address: "osmo1xqw2sl9zk8a6pch0csaw78n4swg5ws8t62wc5qta4gnjxfqg6v2qcs777k".to_string(),
-        });
+        let collections = vec![CollectionInfo {
+            address: COLLECTION.to_string(),
+            host: "osmosis".to_string(),
+            min_lock_duration: None,
+            lock_cooldown: None,
+        }];
 
         InstantiateMsg {
             collections_info: collections,
             ibc_settings: IbcSettings {
                 timeout: 300u64,
-                max_timeouts : 3
+                max_timeouts : 3,
+                emergency_unlock_grace: None,
+                relayer_fees: None,
+                timeout_kind: TimeoutKind::Timestamp,
+                ping_interval: None,
+                retry_backoff: None,
+                prune_grace: None
             },
-            host_chain_prefix: "osmo".to_string(),
+            hosts: vec![HostInfo {
+                label: "osmosis".to_string(),
+                chain_prefix: "osmo".to_string(),
+                height_timeout: None,
+            }],
             lock_credit_settings: crate::datatypes::LockCreditSettings {
-                token: Some(Coin {
-                    denom: "uosmo".to_string(),
-                    amount: 100_000u128.into()
-                }),
-                credit_per_lock: 1u16
+                prices: vec![CreditPrice {
+                    asset: PaymentAsset::Native { denom: "uosmo".to_string() },
+                    amount: 100_000u128.into(),
+                    tiers: vec![]
+                }],
+                credit_per_lock: 1u16,
+                treasury: None,
+                promotions: vec![]
             }
         }
     }
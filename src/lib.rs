@@ -7,5 +7,8 @@ pub mod msg;
 pub mod state;
 pub mod datatypes;
 pub mod ibc;
+pub mod ics721;
+pub mod protocol;
+mod migrations;
 
 pub use crate::error::ContractError;
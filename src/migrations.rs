@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{Addr, Coin, DepsMut, Empty, IbcTimeout, Order, StdResult, Timestamp};
+use cw_storage_plus::{Item, Map};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{datatypes::{ChannelInfo, ChannelProtocol, CollectionInfo, CreditPrice, HostInfo, IbcPacketOutgoing, IbcSettings, LockCreditSettings, LockedToken, PaymentAsset, PendingPacket, PendingStatus, State, UserData}, state::{CHANNELS, CHANNEL_PENDING_REQUESTS, HOST_CHANNELS, LAST_TOKEN_LOCKS, LOCKED_TOKENS, PENDING_PACKETS_REQUESTS, PENDING_REQUEST_TIMEOUTS, STATE, STATE_KEY, TIMED_OUT_UNLOCK_REQUESTS, TOKEN_PENDING_REQUESTS, USERS_DATA}};
+
+//Layouts used up to 0.2.0, a single host identified by its chain prefix and a single channel
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+struct LegacyState {
+    pub collections_info : Vec<LegacyCollectionInfo>,
+    pub admin : Addr,
+    pub ibc_settings : IbcSettings,
+    pub host_chain_prefix : String,
+    pub lock_credit_settings : LegacyLockCreditSettings
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+struct LegacyLockCreditSettings {
+    pub token : Option<Coin>,
+    pub credit_per_lock : u16
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+struct LegacyCollectionInfo {
+    pub address : String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+struct LegacyUserData {
+    pub address : Addr,
+    pub locked_tokens : HashMap<String, Vec<String>>,
+    pub last_lock : u64,
+    pub lock_credits : u16
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+struct LegacyChannelInfo {
+    pub channel_id: String,
+    pub finalized: bool,
+    pub opened_at : u64
+}
+
+const LEGACY_STATE: Item<LegacyState> = Item::new(STATE_KEY);
+const LEGACY_CHANNEL: Item<LegacyChannelInfo> = Item::new("channel");
+const LEGACY_USERS_DATA: Map<Addr, LegacyUserData> = Map::new("users_data");
+const LEGACY_TIMED_OUT_UNLOCK_REQUESTS: Map<(String, Addr), u8> = Map::new("timed_out_unlock_requests"); //(token_id, user)
+const LEGACY_PENDING_PACKETS_REQUESTS: Map<(Addr, u128), IbcPacketOutgoing> = Map::new("packet_requests");
+
+/**
+ * Migrate the storage written by 0.2.0
+ * 1) The single host becomes a host labelled with its chain prefix, every collection is routed to it, the credit token becomes a native price
+ * 2) The single channel is moved to the channels map and bound to that host
+ * 3) Pending packets are wrapped in a PendingPacket record, sent through the single channel with the timestamp timeout, and indexed by the tokens they target
+ * 4) The tokens locked by every user are moved to the token ledger, locked at the last lock of the user
+ * 5) Unlock timeout counters are scoped to the collection, the counter goes to every locked token of the user with that id
+ */
+pub(crate) fn migrate_from_v0_2(deps: DepsMut) -> StdResult<()> {
+    let legacy_state = LEGACY_STATE.load(deps.storage)?;
+    let host_label = legacy_state.host_chain_prefix.clone();
+
+    let state = State {
+        collections_info : legacy_state.collections_info
+            .into_iter()
+            .map(|collection| CollectionInfo { address : collection.address, host : host_label.clone(), min_lock_duration : None, lock_cooldown : None })
+            .collect(),
+        admin : legacy_state.admin,
+        ibc_settings : legacy_state.ibc_settings,
+        hosts : vec![HostInfo { label : host_label.clone(), chain_prefix : legacy_state.host_chain_prefix, height_timeout : None }],
+        lock_credit_settings : LockCreditSettings {
+            prices : legacy_state.lock_credit_settings.token
+                .into_iter()
+                .map(|token| CreditPrice { asset : PaymentAsset::Native { denom : token.denom }, amount : token.amount, tiers : vec![] })
+                .collect(),
+            credit_per_lock : legacy_state.lock_credit_settings.credit_per_lock,
+            treasury : None,
+            promotions : vec![]
+        }
+    };
+    STATE.save(deps.storage, &state)?;
+
+    let legacy_channel = LEGACY_CHANNEL.may_load(deps.storage)?;
+
+    let legacy_requests = LEGACY_PENDING_PACKETS_REQUESTS
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    //Credits spent for a lock were always the configured amount
+    let credits_per_lock = if state.lock_credit_settings.enabled() { state.lock_credit_settings.credit_per_lock } else { 0 };
+
+    for (key, packet) in legacy_requests {
+        let credits = if packet.packet_type.is_lock() { credits_per_lock } else { 0 };
+
+        //Saved in place of the legacy record, the token index is filled directly
+        for token in packet.packet_type.tokens() {
+            TOKEN_PENDING_REQUESTS.save(deps.storage, token, &key)?;
+        }
+
+        let timeout = packet.timestamp + state.ibc_settings.timeout;
+        if let Some(legacy_channel) = &legacy_channel {
+            CHANNEL_PENDING_REQUESTS.save(deps.storage, (legacy_channel.channel_id.clone(), key.0.clone(), key.1), &Empty {})?;
+            PENDING_REQUEST_TIMEOUTS.save(deps.storage, (timeout, key.0.clone(), key.1), &Empty {})?;
+        }
+
+        PENDING_PACKETS_REQUESTS.save(deps.storage, key, &PendingPacket {
+            channel_id : legacy_channel.as_ref().map(|channel| channel.channel_id.clone()).unwrap_or_default(),
+            sent_at : packet.timestamp,
+            credits,
+            status : if legacy_channel.is_some() { PendingStatus::InFlight } else { PendingStatus::Recoverable },
+            timeout : IbcTimeout::with_timestamp(Timestamp::from_seconds(timeout)),
+            packet
+        })?;
+    }
+
+    if let Some(legacy_channel) = legacy_channel {
+        CHANNELS.save(deps.storage, legacy_channel.channel_id.clone(), &ChannelInfo {
+            channel_id : legacy_channel.channel_id.clone(),
+            finalized : legacy_channel.finalized,
+            opened_at : legacy_channel.opened_at,
+            host : Some(host_label.clone()),
+            closed_at : None,
+            protocol : ChannelProtocol::SatelliteV1,
+            fee_enabled : false
+        })?;
+        HOST_CHANNELS.save(deps.storage, host_label, &legacy_channel.channel_id)?;
+        LEGACY_CHANNEL.remove(deps.storage);
+    }
+
+    let legacy_users_data = LEGACY_USERS_DATA
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    for (user, legacy_user_data) in legacy_users_data {
+        for (collection, token_ids) in legacy_user_data.locked_tokens {
+            for token_id in token_ids {
+                LAST_TOKEN_LOCKS.save(deps.storage, (collection.clone(), token_id.clone()), &legacy_user_data.last_lock)?;
+                LOCKED_TOKENS.save(deps.storage, (collection.clone(), token_id), &LockedToken {
+                    owner : user.clone(),
+                    locked_at : legacy_user_data.last_lock,
+                    native_address : None
+                })?;
+            }
+        }
+
+        USERS_DATA.save(deps.storage, user, &UserData {
+            address : legacy_user_data.address,
+            last_lock : legacy_user_data.last_lock,
+            lock_credits : legacy_user_data.lock_credits
+        })?;
+    }
+
+    let legacy_timeouts = LEGACY_TIMED_OUT_UNLOCK_REQUESTS
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    for ((token_id, user), count) in legacy_timeouts {
+        let locked_tokens = LOCKED_TOKENS.idx.owner
+            .prefix(user.clone())
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        for (collection, _) in locked_tokens.into_iter().filter(|(_, locked_token_id)| *locked_token_id == token_id) {
+            TIMED_OUT_UNLOCK_REQUESTS.save(deps.storage, (collection, token_id.clone(), user.clone()), &count)?;
+        }
+        LEGACY_TIMED_OUT_UNLOCK_REQUESTS.remove(deps.storage, (token_id, user));
+    }
+
+    Ok(())
+}
@@ -1,14 +1,15 @@
 
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::Addr;
+use cosmwasm_std::{Addr, Coin};
+use cw20::Cw20ReceiveMsg;
 
-use crate::datatypes::{LockCreditSettings, CollectionInfo, Cw721ReceiveMsg, IbcSettings, PacketType, State, UserData};
+use crate::datatypes::{AssetRevenue, Campaign, CreditQuote, ChannelHealth, ChannelInfo, CollectionInfo, Cw721ReceiveMsg, HostInfo, IbcSettings, LockCreditSettings, HistoryEntry, PacketType, PendingPacket, RequestAttempt, State, TokenLockResponse, TokenStatus, UserDataResponse};
 
 #[cw_serde]
 pub struct InstantiateMsg {
     pub collections_info : Vec<CollectionInfo>,
     pub ibc_settings : IbcSettings,
-    pub host_chain_prefix : String, //e.g orai, osmo, juno, inj etc...
+    pub hosts : Vec<HostInfo>,
     pub lock_credit_settings : LockCreditSettings
 }
 
@@ -17,7 +18,7 @@ pub struct UpdateStatePayload {
     pub collections_info : Option<Vec<CollectionInfo>>,
     pub ibc_settings : Option<IbcSettings>,
     pub admin : Option<Addr>,
-    pub host_chain_prefix : Option<String>, //e.g orai, osmo, juno, inj etc...
+    pub hosts : Option<Vec<HostInfo>>,
     pub lock_credit_settings : Option<LockCreditSettings>
 }
 
@@ -27,11 +28,14 @@ pub struct MigrateMsg {
 }
 
 #[cw_serde]
+#[allow(clippy::large_enum_variant)] //Messages are deserialized once per call, boxing the settings isn't worth it
 pub enum ExecuteMsg {
     ReceiveNft(Cw721ReceiveMsg),
     GetCredits {
         amount : u16
     },
+    //Purchase credits with a cw20 token of the price list, the hook message is a Cw20HookMsg
+    Receive(Cw20ReceiveMsg),
     UnlockToken {
         collection : String,
         token_id : String,
@@ -39,17 +43,71 @@ pub enum ExecuteMsg {
     },
     UpdateStatePayload {
         state_changes : UpdateStatePayload
+    },
+    BindChannel {
+        channel_id : String,
+        host : String,
+        fee_enabled : Option<bool> //The channel stack includes the ICS-29 fee middleware, defaults to false
+    },
+    RecoverClosedChannel {
+        channel_id : String,
+        limit : Option<u16>
+    },
+    EmergencyUnlock {
+        collection : String,
+        token_id : String
+    },
+    //Pull the tokens from the sender wallet (the contract must be an approved operator) and lock them with a single packet
+    LockTokens {
+        tokens : Vec<(String, String)>, //(collection, token_id)
+        native_address : Option<String>
+    },
+    UnlockTokens {
+        tokens : Vec<(String, String)>,
+        native_address : Option<String>
+    },
+    //Send a heartbeat to the host, at most once every ibc_settings.ping_interval per channel
+    Ping {
+        host : String
+    },
+    //Send again an unlock request that timed out or was stranded on a closed channel
+    RetryUnlock {
+        request_id : u128
+    },
+    //Make recoverable up to `limit` in flight requests left without ack nor timeout well past their timeout, callable by anyone
+    PrunePending {
+        limit : Option<u16>
+    },
+    //Admin only, give free credits to every recipient, counted against the budget of `campaign` if any
+    GrantCredits {
+        recipients : Vec<(String, u64)>, //(address, credits)
+        campaign : Option<String>
+    },
+    //Admin only, open an airdrop campaign granting up to `budget` credits
+    CreateCampaign {
+        name : String,
+        budget : u64,
+        per_user_cap : Option<u64>,
+        expires_at : Option<u64>
+    },
+    //Admin or treasury manager, split the credit revenue not withdrawn yet between the treasury recipients
+    WithdrawFees {},
+    //Admin only, clear the consecutive unlock timeouts counted for a token of a user
+    ResetUnlockTimeouts {
+        collection : String,
+        token_id : String,
+        user : Addr
     }
 }
 
 #[cw_serde]
 #[derive(QueryResponses)]
 pub enum QueryMsg {
-    #[returns(UserData)]
+    #[returns(UserDataResponse)]
     GetUserData { 
         address : String
     },
-    #[returns(Vec<String>)]
+    #[returns(Vec<UserDataResponse>)]
     GetAllUsersData {
         start_after : Option<Addr>,
         limit : Option<u16>
@@ -67,10 +125,83 @@ pub enum QueryMsg {
     },
     #[returns(State)]
     GetState { },
-    #[returns(String)]
+    #[returns(TokenStatus)]
     GetTokenStatus {
         user : Addr,
         collection : String,
         token_id : String
+    },
+    #[returns(Vec<ChannelInfo>)]
+    GetChannels {
+        start_after : Option<String>,
+        limit : Option<u16>
+    },
+    #[returns(Vec<Coin>)]
+    GetRelayerFeePool {},
+    //Credit sales revenue collected and withdrawn per asset
+    #[returns(Vec<AssetRevenue>)]
+    GetRevenue {},
+    //Cost of `amount` credits in every accepted asset at the current block time
+    #[returns(Vec<CreditQuote>)]
+    SimulateCreditPurchase {
+        amount : u16
+    },
+    //Full record of a pending request, including the timeout it was sent with
+    #[returns(PendingPacket)]
+    GetPendingRequest {
+        user : Addr,
+        request_id : u128
+    },
+    #[returns(ChannelHealth)]
+    GetChannelHealth {
+        channel_id : String
+    },
+    //Every packet sent for a request, in order
+    #[returns(Vec<RequestAttempt>)]
+    GetRequestAttempts {
+        user : Addr,
+        request_id : u128
+    },
+    //Lock of a token without knowing its owner
+    #[returns(TokenLockResponse)]
+    GetTokenLock {
+        collection : String,
+        token_id : String
+    },
+    //Consecutive unlock timeouts of a token of the user, the token is force unlocked once ibc_settings.max_timeouts is reached
+    #[returns(u8)]
+    GetUnlockTimeouts {
+        collection : String,
+        token_id : String,
+        user : Addr
+    },
+    #[returns(Campaign)]
+    GetCampaign {
+        name : String
+    },
+    #[returns(Vec<Campaign>)]
+    GetCampaigns {
+        start_after : Option<String>,
+        limit : Option<u16>
+    },
+    //Credits granted to a user by a campaign
+    #[returns(u64)]
+    GetCampaignGrant {
+        name : String,
+        user : Addr
+    },
+    //History of the user tokens, oldest first, `start_after` is the sequence of the last entry received
+    #[returns(Vec<HistoryEntry>)]
+    GetUserHistory {
+        user : Addr,
+        start_after : Option<u64>,
+        limit : Option<u16>
+    },
+    #[returns(Vec<HistoryEntry>)]
+    GetTokenHistory {
+        collection : String,
+        token_id : String,
+        start_after : Option<u64>,
+        limit : Option<u16>
     }
 }
\ No newline at end of file
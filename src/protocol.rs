@@ -0,0 +1,138 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{ensure, from_json, to_json_binary, Addr, Binary, StdError, StdResult};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{datatypes::{AckMessage, ChannelProtocol, HostInfo, IbcPacketIncoming, IbcPacketOutgoing, PacketType}, ics721::{self, ICS721_VERSION}};
+
+pub const IBC_APP_VERSION_V1: &str = "gamefi-satellite-protocol-v1";
+pub const IBC_APP_VERSION_V2: &str = "gamefi-satellite-protocol-v2";
+
+//Satellite versions by preference, the highest one shared with the counterparty is used
+const SATELLITE_PROTOCOLS: [ChannelProtocol; 2] = [ChannelProtocol::SatelliteV2, ChannelProtocol::SatelliteV1];
+
+//Since v2 every packet and ack travels in an envelope carrying its schema version
+#[cw_serde]
+pub struct VersionedPayload<T> {
+    pub version : u8,
+    pub payload : T
+}
+
+impl ChannelProtocol {
+    pub fn version(&self) -> &'static str {
+        match self {
+            ChannelProtocol::SatelliteV1 => IBC_APP_VERSION_V1,
+            ChannelProtocol::SatelliteV2 => IBC_APP_VERSION_V2,
+            ChannelProtocol::Ics721 => ICS721_VERSION
+        }
+    }
+
+    pub fn from_version(version : &str) -> Option<ChannelProtocol> {
+        [ChannelProtocol::SatelliteV1, ChannelProtocol::SatelliteV2, ChannelProtocol::Ics721]
+            .into_iter()
+            .find(|protocol| protocol.version() == version)
+    }
+}
+
+/**
+ * Pick the protocol of a channel from the versions proposed by the counterparty.
+ * The proposal is a single version or a comma separated list, the highest satellite version known by both sides wins.
+ * An empty proposal (left empty by the relayer on init) means every satellite version is acceptable.
+ */
+pub(crate) fn negotiate_version(proposal : &str) -> StdResult<ChannelProtocol> {
+    if proposal.is_empty() {
+        return Ok(SATELLITE_PROTOCOLS[0].clone())
+    }
+
+    let proposed : Vec<ChannelProtocol> = proposal
+        .split(',')
+        .filter_map(|version| ChannelProtocol::from_version(version.trim()))
+        .collect();
+
+    SATELLITE_PROTOCOLS
+        .into_iter()
+        .chain([ChannelProtocol::Ics721])
+        .find(|protocol| proposed.contains(protocol))
+        .ok_or_else(|| StdError::generic_err(format!(
+            "Counterparty version must be one of `{IBC_APP_VERSION_V2}`, `{IBC_APP_VERSION_V1}` or `{ICS721_VERSION}`"
+        )))
+}
+
+//Versions advertised when the satellite opens the channel, the counterparty answers with the one it picked
+pub(crate) fn proposed_versions() -> String {
+    SATELLITE_PROTOCOLS
+        .iter()
+        .map(|protocol| protocol.version())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn encode<T : Serialize>(protocol : &ChannelProtocol, payload : &T) -> StdResult<Binary> {
+    match protocol {
+        ChannelProtocol::SatelliteV2 => to_json_binary(&VersionedPayload { version : 2, payload }),
+        _ => to_json_binary(payload)
+    }
+}
+
+fn decode<T : DeserializeOwned>(protocol : &ChannelProtocol, data : &Binary) -> StdResult<T> {
+    match protocol {
+        ChannelProtocol::SatelliteV2 => {
+            let envelope : VersionedPayload<T> = from_json(data)?;
+            ensure!(envelope.version == 2, StdError::generic_err(format!("unsupported packet version {}", envelope.version)));
+            Ok(envelope.payload)
+        },
+        _ => from_json(data)
+    }
+}
+
+pub(crate) fn encode_request(protocol : &ChannelProtocol, request : &IbcPacketOutgoing, host : &HostInfo) -> StdResult<Binary> {
+    match protocol {
+        ChannelProtocol::Ics721 => ics721::encode_request(request, host),
+        _ => encode(protocol, request)
+    }
+}
+
+//Key of the pending request a sent packet belongs to, the satellite protocol carries it in the packet itself
+pub(crate) fn decode_request_key(protocol : &ChannelProtocol, data : &Binary) -> StdResult<(Addr, u128)> {
+    match protocol {
+        ChannelProtocol::Ics721 => ics721::decode_request_key(data),
+        _ => {
+            let packet : IbcPacketOutgoing = decode(protocol, data)?;
+            Ok((packet.packet_type.user().clone(), packet.request_id))
+        }
+    }
+}
+
+//Whether a sent packet carries the given request as it was last sent, an older packet of a retried request doesn't
+pub(crate) fn matches_request(protocol : &ChannelProtocol, data : &Binary, request : &IbcPacketOutgoing) -> bool {
+    match protocol {
+        ChannelProtocol::Ics721 => ics721::matches_request(data, request),
+        _ => decode::<IbcPacketOutgoing>(protocol, data).is_ok_and(|packet| packet == *request)
+    }
+}
+
+//Heartbeats have no pending record, they are recognized from the packet itself
+pub(crate) fn is_ping(protocol : &ChannelProtocol, data : &Binary) -> bool {
+    match protocol {
+        ChannelProtocol::Ics721 => false,
+        _ => decode::<IbcPacketOutgoing>(protocol, data).is_ok_and(|packet| matches!(packet.packet_type, PacketType::Ping { .. }))
+    }
+}
+
+pub(crate) fn decode_ack(protocol : &ChannelProtocol, data : &Binary) -> StdResult<AckMessage> {
+    match protocol {
+        ChannelProtocol::Ics721 => ics721::decode_ack(data),
+        _ => decode(protocol, data)
+    }
+}
+
+pub(crate) fn encode_ack(protocol : &ChannelProtocol, ack : &AckMessage) -> StdResult<Binary> {
+    match (protocol, ack) {
+        (ChannelProtocol::Ics721, AckMessage::Error { error }) => ics721::ack_fail(error.clone()),
+        (ChannelProtocol::Ics721, _) => ics721::ack_success(),
+        _ => encode(protocol, ack)
+    }
+}
+
+pub(crate) fn decode_command(protocol : &ChannelProtocol, data : &Binary) -> StdResult<IbcPacketIncoming> {
+    decode(protocol, data)
+}
@@ -1,72 +1,49 @@
-use cosmwasm_std::{Addr, Deps, Order, StdResult};
+use cosmwasm_std::{ensure, Addr, Coin, Deps, Env, Order, StdResult};
 use cw_storage_plus::Bound;
 
-use crate::{datatypes::{PacketType, State, UserData}, helpers::ensure_error, state::{PENDING_PACKETS_REQUESTS, STATE, USERS_DATA}};
+use crate::{datatypes::{AssetRevenue, Campaign, CreditQuote, ChannelHealth, ChannelInfo, HistoryEntry, PacketType, PendingPacket, RequestAttempt, State, TokenLockResponse, TokenStatus, UserDataResponse}, helpers::{ensure_error, host_address, load_token_status, user_data_response}, state::{CAMPAIGNS, CAMPAIGN_GRANTS, CHANNELS, HISTORY, LOCKED_TOKENS, TIMED_OUT_UNLOCK_REQUESTS, TOKEN_PENDING_REQUESTS, CHANNELS_HEALTH, PENDING_PACKETS_REQUESTS, RELAYER_FEE_POOL, REQUEST_ATTEMPTS, REVENUE, STATE, USERS_DATA}};
 
-pub(crate) fn get_user_data(deps : Deps, address : String) -> StdResult<UserData> {
+pub(crate) fn get_user_data(deps : Deps, address : String) -> StdResult<UserDataResponse> {
     let valid_address = match deps.api.addr_validate(&address) {
         Ok(address) => address,
         Err(_) => return Err(ensure_error("Address not valid".to_string())),
     };
 
-    return Ok(USERS_DATA.load(deps.storage, valid_address)?);
+    user_data_response(deps.storage, USERS_DATA.load(deps.storage, valid_address)?)
 }
 
 pub(crate) fn get_state(deps : Deps) -> StdResult<State> {
-    Ok(STATE.load(deps.storage)?)
+    STATE.load(deps.storage)
 }
 
-pub(crate) fn get_all_users_data(deps : Deps, start_after : Option<Addr>, limit : Option<u16>) -> StdResult<Vec<UserData>> {
+pub(crate) fn get_all_users_data(deps : Deps, start_after : Option<Addr>, limit : Option<u16>) -> StdResult<Vec<UserDataResponse>> {
 
     let start = start_after.map(Bound::exclusive);
     let limit = limit.unwrap_or(10) as usize;
 
-    Ok(
-        USERS_DATA.range(
-          deps.storage, 
-            start,
-            None, 
-                Order::Ascending
-            )
-            .take(limit)
-            .map(|x| x.map(|x| x.1))
-            .collect::<StdResult<Vec<_>>>()?
+    USERS_DATA.range(
+        deps.storage, 
+        start,
+        None, 
+        Order::Ascending
     )
+    .take(limit)
+    .map(|x| x.and_then(|x| user_data_response(deps.storage, x.1)))
+    .collect::<StdResult<Vec<_>>>()
 }
 
+//Status of the token for the given user, a token locked or pending for another user is unlocked from its point of view
 pub(crate) fn get_token_status(
     deps: Deps,
     user: Addr,
     collection: String,
     token_id: String,
-) -> StdResult<String> {
-    let user_data = USERS_DATA.load(deps.storage, user.clone())?;
-
-    let pending_user_packets = PENDING_PACKETS_REQUESTS
-        .prefix(user.clone())
-        .range(deps.storage, None, None, Order::Ascending)
-        .map(|res| res.map(|(_, packet)| packet.packet_type))
-        .collect::<StdResult<Vec<_>>>()?;
-
-    if pending_user_packets.iter().any(|res| res.to_string() == "lock_request") {
-        return Ok("lock_in_progres".to_string());
-    }
-
-    if pending_user_packets.iter().any(|res| res.to_string() == "unlock_request") {
-        return Ok("unlock_in_progres".to_string());
-    }
-
-    let token_status = if user_data
-        .locked_tokens
-        .get(&collection)
-        .map_or(false, |tokens| tokens.contains(&token_id))
-    {
-        "locked"
-    } else {
-        "unlocked"
-    };
+) -> StdResult<TokenStatus> {
+    USERS_DATA.load(deps.storage, user.clone())?;
+
+    let (status, token_user) = load_token_status(deps.storage, &collection, &token_id)?;
 
-    Ok(token_status.to_string())
+    Ok(if token_user == Some(user) { status } else { TokenStatus::Unlocked })
 }
 
 pub(crate) fn get_all_pending_packets(deps : Deps, start_after : Option<(Addr , u128)>, limit : Option<u16>) -> StdResult<Vec<PacketType>> {
@@ -74,17 +51,15 @@ pub(crate) fn get_all_pending_packets(deps : Deps, start_after : Option<(Addr ,
     let start = start_after.map(Bound::exclusive);
     let limit = limit.unwrap_or(10) as usize;
 
-    Ok(
-        PENDING_PACKETS_REQUESTS.range(
-            deps.storage, 
-            start,
-            None, 
-                Order::Descending
-            )
-            .take(limit)
-            .map(|res| res.map(|(_, packet)| packet.packet_type))
-            .collect::<StdResult<Vec<_>>>()?
+    PENDING_PACKETS_REQUESTS.range(
+        deps.storage, 
+        start,
+        None, 
+        Order::Descending
     )
+    .take(limit)
+    .map(|res| res.map(|(_, pending)| pending.packet.packet_type))
+    .collect::<StdResult<Vec<_>>>()
 }
 
 pub(crate) fn get_user_pending_packets(deps : Deps, start_after : Option<u128>, limit : Option<u16>, user: Addr) -> StdResult<Vec<PacketType>> {
@@ -92,15 +67,167 @@ pub(crate) fn get_user_pending_packets(deps : Deps, start_after : Option<u128>,
     let start = start_after.map(Bound::exclusive);
     let limit = limit.unwrap_or(10) as usize;
 
-    Ok(
-        PENDING_PACKETS_REQUESTS.prefix(user).range(
-            deps.storage, 
-            start,
-            None, 
-                Order::Descending
-            )
-            .take(limit)
-            .map(|res| res.map(|(_, packet)| packet.packet_type))
-            .collect::<StdResult<Vec<_>>>()?
+    PENDING_PACKETS_REQUESTS.prefix(user).range(
+        deps.storage, 
+        start,
+        None, 
+        Order::Descending
     )
-}
\ No newline at end of file
+    .take(limit)
+    .map(|res| res.map(|(_, pending)| pending.packet.packet_type))
+    .collect::<StdResult<Vec<_>>>()
+}
+
+pub(crate) fn get_channels(deps : Deps, start_after : Option<String>, limit : Option<u16>) -> StdResult<Vec<ChannelInfo>> {
+
+    let start = start_after.map(Bound::exclusive);
+    let limit = limit.unwrap_or(10) as usize;
+
+    CHANNELS.range(
+        deps.storage, 
+        start,
+        None, 
+        Order::Ascending
+    )
+    .take(limit)
+    .map(|res| res.map(|(_, channel)| channel))
+    .collect::<StdResult<Vec<_>>>()
+}
+pub(crate) fn get_relayer_fee_pool(deps : Deps) -> StdResult<Vec<Coin>> {
+    RELAYER_FEE_POOL.range(
+        deps.storage,
+        None,
+        None,
+        Order::Ascending
+    )
+    .map(|res| res.map(|(denom, amount)| Coin::new(amount, denom)))
+    .collect::<StdResult<Vec<_>>>()
+}
+
+pub(crate) fn get_revenue(deps : Deps) -> StdResult<Vec<AssetRevenue>> {
+    REVENUE.range(
+        deps.storage,
+        None,
+        None,
+        Order::Ascending
+    )
+    .map(|res| res.map(|(_, revenue)| revenue))
+    .collect::<StdResult<Vec<_>>>()
+}
+
+pub(crate) fn simulate_credit_purchase(deps : Deps, env : Env, amount : u16) -> StdResult<Vec<CreditQuote>> {
+    let state = STATE.load(deps.storage)?;
+
+    state.lock_credit_settings.prices
+        .iter()
+        .map(|price| state.lock_credit_settings.quote(&price.asset, amount, env.block.time.seconds()))
+        .collect::<StdResult<Vec<_>>>()
+}
+
+pub(crate) fn get_pending_request(deps : Deps, user : Addr, request_id : u128) -> StdResult<PendingPacket> {
+    PENDING_PACKETS_REQUESTS.may_load(deps.storage, (user, request_id))?
+        .ok_or_else(|| ensure_error(format!("Request {} not found", request_id)))
+}
+
+pub(crate) fn get_channel_health(deps : Deps, channel_id : String) -> StdResult<ChannelHealth> {
+    ensure!(CHANNELS.has(deps.storage, channel_id.clone()), ensure_error(format!("Channel {} not found", channel_id)));
+
+    Ok(CHANNELS_HEALTH.may_load(deps.storage, channel_id)?.unwrap_or_default())
+}
+
+pub(crate) fn get_request_attempts(deps : Deps, user : Addr, request_id : u128) -> StdResult<Vec<RequestAttempt>> {
+    Ok(REQUEST_ATTEMPTS.may_load(deps.storage, (user, request_id))?.unwrap_or_default())
+}
+
+/**
+ * Lock of a token found from the token alone, the owner is not needed.
+ * The host address is where the token lives on the host while locked, the pending request is the one currently targeting the token.
+ */
+pub(crate) fn get_token_lock(deps : Deps, collection : String, token_id : String) -> StdResult<TokenLockResponse> {
+    let token_key = (collection.clone(), token_id.clone());
+    let (status, token_user) = load_token_status(deps.storage, &collection, &token_id)?;
+    let locked_token = LOCKED_TOKENS.may_load(deps.storage, token_key.clone())?;
+    let pending_request = match TOKEN_PENDING_REQUESTS.may_load(deps.storage, token_key)? {
+        Some(request_key) => Some((request_key.1, PENDING_PACKETS_REQUESTS.load(deps.storage, request_key)?)),
+        None => None
+    };
+
+    //The token is received on the host by the native address given at lock time, if any
+    let native_address = match (&locked_token, &pending_request) {
+        (Some(locked_token), _) => locked_token.native_address.clone(),
+        (None, Some((_, pending))) => pending.packet.packet_type.native_address().cloned(),
+        (None, None) => None
+    };
+
+    //A released token has no owner anymore, the user is only the one who reclaimed it
+    let owner = match status {
+        TokenStatus::Unlocked | TokenStatus::EmergencyReleased => None,
+        _ => token_user
+    };
+
+    let state = STATE.load(deps.storage)?;
+    let host = state.collections_info
+        .iter()
+        .find(|collection_info| collection_info.address == collection)
+        .and_then(|collection_info| state.hosts.iter().find(|host| host.label == collection_info.host));
+
+    let host_address = match (&owner, host) {
+        (Some(owner), Some(host)) => Some(host_address(host, owner, native_address.as_ref())?),
+        _ => None
+    };
+
+    Ok(TokenLockResponse {
+        owner,
+        locked_at : locked_token.map(|locked_token| locked_token.locked_at),
+        status,
+        pending_request_id : pending_request.map(|(request_id, _)| request_id),
+        host_address
+    })
+}
+
+pub(crate) fn get_unlock_timeouts(deps : Deps, collection : String, token_id : String, user : Addr) -> StdResult<u8> {
+    Ok(TIMED_OUT_UNLOCK_REQUESTS.may_load(deps.storage, (collection, token_id, user))?.unwrap_or_default())
+}
+
+pub(crate) fn get_campaign(deps : Deps, name : String) -> StdResult<Campaign> {
+    CAMPAIGNS.may_load(deps.storage, name.clone())?
+        .ok_or_else(|| ensure_error(format!("Campaign {} not found", name)))
+}
+
+pub(crate) fn get_campaigns(deps : Deps, start_after : Option<String>, limit : Option<u16>) -> StdResult<Vec<Campaign>> {
+    let start = start_after.map(Bound::exclusive);
+    let limit = limit.unwrap_or(10) as usize;
+
+    CAMPAIGNS.range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|x| x.map(|x| x.1))
+        .collect::<StdResult<Vec<_>>>()
+}
+
+pub(crate) fn get_campaign_grant(deps : Deps, name : String, user : Addr) -> StdResult<u64> {
+    Ok(CAMPAIGN_GRANTS.may_load(deps.storage, (name, user))?.unwrap_or_default())
+}
+
+pub(crate) fn get_user_history(deps : Deps, user : Addr, start_after : Option<u64>, limit : Option<u16>) -> StdResult<Vec<HistoryEntry>> {
+    let start = start_after.map(Bound::exclusive);
+    let limit = limit.unwrap_or(10) as usize;
+
+    HISTORY.idx.user
+        .prefix(user)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|x| x.map(|x| x.1))
+        .collect::<StdResult<Vec<_>>>()
+}
+
+pub(crate) fn get_token_history(deps : Deps, collection : String, token_id : String, start_after : Option<u64>, limit : Option<u16>) -> StdResult<Vec<HistoryEntry>> {
+    let start = start_after.map(Bound::exclusive);
+    let limit = limit.unwrap_or(10) as usize;
+
+    HISTORY.idx.token
+        .prefix((collection, token_id))
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|x| x.map(|x| x.1))
+        .collect::<StdResult<Vec<_>>>()
+}
@@ -1,14 +1,79 @@
-use crate::datatypes::{ChannelInfo, IbcPacketOutgoing, State, UserData};
-use cosmwasm_std::Addr;
-use cw_storage_plus::{Item, Map};
+use crate::datatypes::{AssetRevenue, Campaign, EscrowedFee, ChannelHealth, ChannelInfo, EmergencyRelease, HistoryEntry, HostHeight, LockedToken, PendingPacket, RequestAttempt, State, UserData};
+use cosmwasm_std::{Addr, Empty, Uint128};
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex};
 
 pub const STATE_KEY: &str = "state";
 
 pub const STATE: Item<State> = Item::new(STATE_KEY);
-pub const UNIQUE_PACKETS_REQUEST_ID : Item<u128> = Item::new(&"packets_request_id");
-pub const CHANNEL: Item<ChannelInfo> = Item::new(&"channel");
+pub const UNIQUE_PACKETS_REQUEST_ID : Item<u128> = Item::new("packets_request_id");
+pub const CHANNELS: Map<String, ChannelInfo> = Map::new("channels");
+pub const HOST_CHANNELS: Map<String, String> = Map::new("host_channels"); //host label -> bound channel id
+pub const CHANNELS_HEALTH: Map<String, ChannelHealth> = Map::new("channels_health");
+pub const HOST_HEIGHTS: Map<String, HostHeight> = Map::new("host_heights"); //host label -> last block height reported by the host
 
-pub const PENDING_PACKETS_REQUESTS : Map<(Addr, u128), IbcPacketOutgoing> = Map::new("packet_requests");
+pub const PENDING_PACKETS_REQUESTS : Map<(Addr, u128), PendingPacket> = Map::new("packet_requests");
+pub const LAST_TOKEN_LOCKS : Map<(String, String), u64> = Map::new("last_token_locks"); //(collection, token_id) -> last lock time, kept after the unlock
+pub const TOKEN_PENDING_REQUESTS : Map<(String, String), (Addr, u128)> = Map::new("token_pending_requests"); //(collection, token_id) -> key of the pending request targeting the token
+pub const CHANNEL_PENDING_REQUESTS : Map<(String, Addr, u128), Empty> = Map::new("channel_pending_requests"); //(channel_id, user, request_id) of the requests in flight on the channel
+pub const PENDING_REQUEST_TIMEOUTS : Map<(u64, Addr, u128), Empty> = Map::new("pending_request_timeouts"); //(timeout timestamp, user, request_id) of the requests in flight, soonest expiry first
+pub const REQUEST_ATTEMPTS : Map<(Addr, u128), Vec<RequestAttempt>> = Map::new("request_attempts"); //Kept after the request is settled, to trace its packets
 pub const USERS_DATA : Map<Addr, UserData> = Map::new("users_data");
 
-pub const TIMED_OUT_UNLOCK_REQUESTS : Map<(String, Addr), u8> = Map::new("timed_out_unlock_requests");
\ No newline at end of file
+pub const TIMED_OUT_UNLOCK_REQUESTS : Map<(String, String, Addr), u8> = Map::new("unlock_timeouts"); //(collection, token_id, user) -> consecutive unlock timeouts
+pub const EMERGENCY_RELEASES : Map<(String, String), EmergencyRelease> = Map::new("emergency_releases"); //(collection, token_id)
+pub const HISTORY_SEQUENCE : Item<u64> = Item::new("history_sequence");
+pub const CAMPAIGNS : Map<String, Campaign> = Map::new("campaigns");
+pub const CAMPAIGN_GRANTS : Map<(String, Addr), u64> = Map::new("campaign_grants"); //(campaign, user) -> credits granted to the user
+pub const RELAYER_FEE_POOL : Map<String, Uint128> = Map::new("relayer_fee_pool"); //denom -> credit revenue set aside for relayer fees
+pub const RELAYER_FEE_ESCROWS : Map<(Addr, u128), Vec<EscrowedFee>> = Map::new("relayer_fee_escrows"); //(user, request_id) -> fees escrowed for the packets of the request, until their ack or timeout
+pub const REVENUE : Map<String, AssetRevenue> = Map::new("revenue"); //asset -> credit sales paid in the asset
+
+pub struct LockedTokenIndexes<'a> {
+    pub owner : MultiIndex<'a, Addr, LockedToken, (String, String)>
+}
+
+impl IndexList<LockedToken> for LockedTokenIndexes<'_> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<LockedToken>> + '_> {
+        let indexes : Vec<&dyn Index<LockedToken>> = vec![&self.owner];
+        Box::new(indexes.into_iter())
+    }
+}
+
+fn locked_token_owner(_pk : &[u8], token : &LockedToken) -> Addr {
+    token.owner.clone()
+}
+
+//Token ledger, (collection, token_id) -> locked token, indexed by owner
+pub const LOCKED_TOKENS : IndexedMap<(String, String), LockedToken, LockedTokenIndexes> = IndexedMap::new(
+    "locked_tokens",
+    LockedTokenIndexes { owner : MultiIndex::new(locked_token_owner, "locked_tokens", "locked_tokens__owner") }
+);
+
+pub struct HistoryIndexes<'a> {
+    pub user : MultiIndex<'a, Addr, HistoryEntry, u64>,
+    pub token : MultiIndex<'a, (String, String), HistoryEntry, u64>
+}
+
+impl IndexList<HistoryEntry> for HistoryIndexes<'_> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<HistoryEntry>> + '_> {
+        let indexes : Vec<&dyn Index<HistoryEntry>> = vec![&self.user, &self.token];
+        Box::new(indexes.into_iter())
+    }
+}
+
+fn history_user(_pk : &[u8], entry : &HistoryEntry) -> Addr {
+    entry.user.clone()
+}
+
+fn history_token(_pk : &[u8], entry : &HistoryEntry) -> (String, String) {
+    (entry.collection.clone(), entry.token_id.clone())
+}
+
+//Append-only history, sequence -> entry, indexed by user and by (collection, token_id)
+pub const HISTORY : IndexedMap<u64, HistoryEntry, HistoryIndexes> = IndexedMap::new(
+    "history",
+    HistoryIndexes {
+        user : MultiIndex::new(history_user, "history", "history__user"),
+        token : MultiIndex::new(history_token, "history", "history__token")
+    }
+);